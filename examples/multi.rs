@@ -1,8 +1,20 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use last_stage::{
+    Producer, ProducerRunnable, Emit,
+    ProducerConsumer, ProducerConsumerRunnable,
+    Consumer, ConsumerRunnable,
+    State, DispatcherType, Shutdown
+};
+
+
 
 #[tokio::main]
 async fn main() {
 
-    let(shutdown_sender, shutdown_recv) = channel();
+    let mut shutdown = Shutdown::new();
 
 
     // ------------------------------------
@@ -19,39 +31,42 @@ async fn main() {
 
 
     // Run Consumer
-    let log_chan = ConsumerRunnable::new(Box::new(Log)).run(100);
+    let (log_chan, _) = ConsumerRunnable::new(Box::new(Log)).with_shutdown(&shutdown).run(100);
 
 
     // Run ProducerConsumer
-    let filter_chan1 = 
-        ProducerConsumerRunnable::new(Box::new(FilterByAge), vec![log_chan.clone()], None).unwrap().run(100);
+    let (filter_chan1, _, _) = 
+        ProducerConsumerRunnable::new(Box::new(FilterByAge), vec![log_chan.clone()], None).unwrap().with_shutdown(&shutdown).run(100);
 
     // Run ProducerConsumer
-    let filter_chan2 = 
-        ProducerConsumerRunnable::new(Box::new(FilterByAge), vec![log_chan.clone()], None).unwrap().run(100);
+    let (filter_chan2, _, _) = 
+        ProducerConsumerRunnable::new(Box::new(FilterByAge), vec![log_chan.clone()], None).unwrap().with_shutdown(&shutdown).run(100);
 
 
     // Run ProducerConsumer
-    let filter_chan3 = 
-        ProducerConsumerRunnable::new(Box::new(FilterByAge), vec![log_chan.clone()], None).unwrap().run(100);
+    let (filter_chan3, _, _) = 
+        ProducerConsumerRunnable::new(Box::new(FilterByAge), vec![log_chan.clone()], None).unwrap().with_shutdown(&shutdown).run(100);
 
 
     // Run ProducerConsumer
-    let filter_chan4 = 
-        ProducerConsumerRunnable::new(Box::new(FilterByAge), vec![log_chan], None).unwrap().run(100);
+    let (filter_chan4, _, _) = 
+        ProducerConsumerRunnable::new(Box::new(FilterByAge), vec![log_chan], None).unwrap().with_shutdown(&shutdown).run(100);
 
 
 
 
     // Run Producer
-    let _ = ProducerRunnable::new(Box::new(Prod), 
+    let (_producer, _) = ProducerRunnable::new(Box::new(Prod), 
                                   vec![filter_chan1, filter_chan2, filter_chan3, filter_chan4], 
                                   Some(DispatcherType::RoundRobin), 
                                   100, 
-                                  shutdown_recv).unwrap().run();
+                                  shutdown.producer()).unwrap().with_shutdown(&shutdown).run();
 
     
     tokio::time::sleep(Duration::from_secs(10)).await;
+
+    // stop producer and wait until all events consumed
+    shutdown.shutdown(Duration::from_secs(5)).await.unwrap();
 }
 
 
@@ -65,12 +80,13 @@ struct Prod;
 
 #[async_trait]
 impl Producer<ProdEvent> for Prod {
-    async fn init(&mut self) {}
-    async fn terminate(&mut self) {}
+    type Error = ();
 
-    async fn handle_demand(&mut self, max_demand: usize) -> Vec<ProdEvent> {
-        (0..max_demand as i32)
-            .into_iter()
+    async fn init(&mut self) -> Result<(), ()> { Ok(()) }
+    async fn terminate(&mut self) -> Result<(), ()> { Ok(()) }
+
+    async fn handle_demand(&mut self, max_demand: usize) -> Result<Emit<ProdEvent>, ()> {
+        Ok(Emit::Events((0..max_demand as i32)
             .map(|i| {
                 
                 ProdEvent { 
@@ -79,7 +95,7 @@ impl Producer<ProdEvent> for Prod {
                 }
 
             })
-            .collect()
+            .collect()))
     }
 
 } 
@@ -92,14 +108,16 @@ struct FilterByAge;
 
 #[async_trait]
 impl ProducerConsumer<ProdEvent, ProdEvent> for FilterByAge {
-    async fn init(&mut self) {}
-    async fn terminate(&mut self) {}
+    type Error = ();
 
-    async fn handle_events(&mut self, events: Vec<ProdEvent>) -> Vec<ProdEvent> {
-        events
+    async fn init(&mut self) -> Result<(), ()> { Ok(()) }
+    async fn terminate(&mut self) -> Result<(), ()> { Ok(()) }
+
+    async fn handle_events(&mut self, events: Vec<ProdEvent>) -> Result<Vec<ProdEvent>, ()> {
+        Ok(events
             .into_iter()
             .filter(|pe| pe.age > 25 && pe.age < 32)
-            .collect()
+            .collect())
     }
 
     
@@ -113,17 +131,19 @@ struct Log;
 
 #[async_trait]
 impl Consumer<ProdEvent> for Log {
-    async fn init(&mut self) {}
-    async fn terminate(&mut self) {}
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> { Ok(()) }
+    async fn terminate(&mut self) -> Result<(), ()> { Ok(()) }
 
-    async fn handle_events(&mut self, events: Vec<ProdEvent>) -> State<ProdEvent> {
+    async fn handle_events(&mut self, events: Vec<ProdEvent>) -> Result<State<ProdEvent>, ()> {
         events
             .into_iter()
             .for_each(|pe| {
                 println!("==> {} -> {}", pe.funame, pe.age)
             });
         
-        State::Continue
+        Ok(State::Continue)
     }  
 
 
@@ -0,0 +1,123 @@
+use async_trait::async_trait;
+
+use last_stage::{
+    Producer, Emit,
+    ProducerConsumer,
+    Consumer,
+    State, DispatcherType, Pipeline
+};
+
+
+
+#[tokio::main]
+async fn main() {
+
+    // ------------------------------------
+    //
+    //            -----> FilterByAge   \
+    //           /                      \--> Log
+    // Producer / -----> FilterByAge     
+    //          \                       /--> Log
+    //           \-----> FilterByAge   /
+    //
+    // Log partitioned by age
+    //
+    // --------------------------------------
+
+
+    let pipeline = Pipeline::from(Prod { batches: 10 })
+                        .via(|| FilterByAge, 3)
+                        .dispatcher(|| DispatcherType::partition(|pe: &ProdEvent| pe.age))
+                        .to(|| Log, 2)
+                        .unwrap();
+
+
+    // producer emit Done after 10 batches,
+    // wait until all events consumed
+    pipeline.join().await.unwrap();
+}
+
+
+#[derive(Clone)]
+struct ProdEvent {
+    pub funame: String,
+    pub age: i32
+}
+
+struct Prod {
+    batches: usize
+}
+
+#[async_trait]
+impl Producer<ProdEvent> for Prod {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> { Ok(()) }
+    async fn terminate(&mut self) -> Result<(), ()> { Ok(()) }
+
+    async fn handle_demand(&mut self, max_demand: usize) -> Result<Emit<ProdEvent>, ()> {
+        let events = (0..max_demand as i32)
+            .map(|i| {
+                
+                ProdEvent { 
+                    funame: format!("DanyalMh-{}", i), 
+                    age: (i + 30) % 35 
+                }
+
+            })
+            .collect();
+
+        self.batches -= 1;
+        if self.batches == 0 {
+            return Ok(Emit::Done(events))
+        }
+
+        Ok(Emit::Events(events))
+    }
+
+} 
+
+
+// -------------------------------------------
+
+
+struct FilterByAge;
+
+#[async_trait]
+impl ProducerConsumer<ProdEvent, ProdEvent> for FilterByAge {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> { Ok(()) }
+    async fn terminate(&mut self) -> Result<(), ()> { Ok(()) }
+
+    async fn handle_events(&mut self, events: Vec<ProdEvent>) -> Result<Vec<ProdEvent>, ()> {
+        Ok(events
+            .into_iter()
+            .filter(|pe| pe.age > 25 && pe.age < 32)
+            .collect())
+    }
+
+} 
+
+
+
+struct Log;
+
+#[async_trait]
+impl Consumer<ProdEvent> for Log {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> { Ok(()) }
+    async fn terminate(&mut self) -> Result<(), ()> { Ok(()) }
+
+    async fn handle_events(&mut self, events: Vec<ProdEvent>) -> Result<State<ProdEvent>, ()> {
+        events
+            .into_iter()
+            .for_each(|pe| {
+                println!("==> {} -> {}", pe.funame, pe.age)
+            });
+        
+        Ok(State::Continue)
+    }  
+
+}
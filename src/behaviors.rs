@@ -1,223 +1,411 @@
+use std::hash::Hash;
+use std::time::Duration;
 
+use tokio::sync::{mpsc, oneshot};
 
-
-use tokio::sync::mpsc::Sender;
+use self::dispatch::Dispatch;
+use self::shared::{Shared, Sharing};
+use self::subscription::Subscription;
 
 pub mod producer;
 pub mod consumer;
 pub mod producer_consumer;
+pub mod subscription;
+pub mod shared;
+pub mod shutdown;
+pub mod stage;
+pub mod supervisor;
+pub mod error;
+pub mod idle;
+pub mod batcher;
+pub mod window;
+pub mod pipeline;
+pub mod topology;
+pub mod metrics;
+pub mod message;
+pub mod dead_letter;
+pub mod retry;
+pub mod dispatch;
+pub mod router;
+mod trace;
+mod partition;
+mod modes;
 
 
 
 #[derive(Debug)]
 pub enum Status {
     SenderNotFound,
-    SendersRepetive
+    SendersRepetive,
+    InvalidDemand,
+    LastSender,
+    StageStopped,
+    DeadlineElapsed,
+    MaxRestarts,
+    StageFailed
 }
 
 
-/// Vec<Out> is events produced but not exit any channel to consume it 
-pub struct DestinationDown<Out>(Vec<Out>);
+/// Vec<Out> is events produced but not exit any channel to consume it
+pub struct DestinationDown<Out>(pub Vec<Out>);
 
 
 
 
 
-pub enum DispatcherType {
+pub enum DispatcherType<Out> {
     RoundRobin,
-    Broadcast
+
+    /// send events to all subscribers, terminated
+    /// subscribers removed, when last one removed
+    /// return DestinationDown,
+    /// create it by `DispatcherType::broadcast`
+    Broadcast(fn(&[Out]) -> Vec<Out>),
+
+    /// like Broadcast, but if any subscriber terminate
+    /// dispatcher return DestinationDown and stage stop,
+    /// create it by `DispatcherType::broadcast_strict`
+    BroadcastStrict(fn(&[Out]) -> Vec<Out>),
+
+    /// like Broadcast, for shared batches, every subscriber get
+    /// pointer copies of same batches and demand counted by
+    /// events in batches, create it by `DispatcherType::broadcast_shared`
+    BroadcastShared(Sharing<Out>),
+
+    /// every event go to subscriber chosen by consistent hashing
+    /// of its key, all events with same key go to same subscriber,
+    /// create it by `DispatcherType::partition`
+    Partition(Box<dyn Fn(&Out) -> u64 + Send + Sync>),
+
+    /// send events to least loaded subscriber, one with most
+    /// free channel slots, when every subscriber asked demand
+    /// has full channel wait for free slot or new demand
+    LeastLoaded,
+
+    /// any implementation of Dispatch,
+    /// create it by `DispatcherType::custom`
+    Custom(Box<dyn Dispatch<Out>>)
 }
 
 
-struct Dispatcher<Out> {
-    c: usize,
-    subscribe_to: Vec<Sender<Vec<Out>>>,
-    dispatcher_type: DispatcherType
+impl<Out: Clone> DispatcherType<Out> {
+
+    /// Broadcast dispatcher, only mode need `Out: Clone`
+    ///
+    /// every subscriber except last get a clone of batch,
+    /// for zero-copy fan-out see `DispatcherType::broadcast_shared`
+    pub fn broadcast() -> Self {
+        DispatcherType::Broadcast(<[Out]>::to_vec)
+    }
+
+    /// BroadcastStrict dispatcher, see `DispatcherType::broadcast`
+    pub fn broadcast_strict() -> Self {
+        DispatcherType::BroadcastStrict(<[Out]>::to_vec)
+    }
 }
 
-impl<Out> Dispatcher<Out> 
-where
-    Out: Clone + Send
-{
-    
 
-    pub fn new(subscribe_to: Vec<Sender<Vec<Out>>>, 
-               dispatcher_type: DispatcherType) -> Result<Self, Status> {
+impl<T: Send + Sync> DispatcherType<Shared<T>> {
 
-        // Check destinations to not be repetive
-        if let Err(_) = Dispatcher::check(&subscribe_to) {
-            return Err(Status::SendersRepetive);
-        }
+    /// Broadcast dispatcher of shared batches, every subscriber
+    /// get same batches and fan-out only copy pointers,
+    /// events not need be Clone, see `Shared`
+    ///
+    /// stopped subscribers removed, demand of subscribers
+    /// counted by events in batches
+    pub fn broadcast_shared() -> Self {
+        DispatcherType::BroadcastShared(Sharing::new())
+    }
+}
 
-        Ok(Dispatcher { 
-            c: 0, 
-            subscribe_to,
-            dispatcher_type
-        })
+
+impl<Out> DispatcherType<Out> {
+
+    /// Partition dispatcher with key extracted from event
+    ///
+    /// ```rust,ignore
+    /// DispatcherType::partition(|event: &UserEvent| event.user_id)
+    /// ```
+    pub fn partition<K, F>(key: F) -> Self
+    where
+        F: Fn(&Out) -> K + Send + Sync + 'static,
+        K: Hash
+    {
+        DispatcherType::Partition(partition::partition_key(key))
     }
+}
 
 
-    #[inline]
-    pub async fn dispatch(&mut self, events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
-        match self.dispatcher_type {
-            DispatcherType::RoundRobin => {
-                return self.roundrobin(events).await
-            }
-            DispatcherType::Broadcast => {
-                self.broadcast(events).await;
-                Ok(())   
-            }
+impl<Out: Send + 'static> DispatcherType<Out> {
+
+    /// Custom dispatcher, see `Dispatch`
+    pub fn custom<D>(dispatch: D) -> Self
+    where
+        D: Dispatch<Out> + 'static
+    {
+        DispatcherType::Custom(Box::new(dispatch))
+    }
+
+    /// name of dispatcher mode
+    pub fn name(&self) -> &'static str {
+        match self {
+            DispatcherType::RoundRobin => "RoundRobin",
+            DispatcherType::Broadcast(_) => "Broadcast",
+            DispatcherType::BroadcastStrict(_) => "BroadcastStrict",
+            DispatcherType::BroadcastShared(_) => "BroadcastShared",
+            DispatcherType::Partition(_) => "Partition",
+            DispatcherType::LeastLoaded => "LeastLoaded",
+            DispatcherType::Custom(dispatch) => dispatch.name()
         }
     }
 
+    /// Dispatch implementation of mode, subscribers not added yet
+    fn into_dispatch(self) -> Box<dyn Dispatch<Out>> {
+        match self {
+            DispatcherType::RoundRobin => Box::new(modes::RoundRobin::new()),
+            DispatcherType::Broadcast(clone) => Box::new(modes::Broadcast::new(clone, false)),
+            DispatcherType::BroadcastStrict(clone) => Box::new(modes::Broadcast::new(clone, true)),
+            DispatcherType::BroadcastShared(sharing) => Box::new(modes::BroadcastShared::new(sharing)),
+            DispatcherType::Partition(key) => Box::new(modes::Partition::new(key)),
+            DispatcherType::LeastLoaded => Box::new(modes::LeastLoaded::new()),
+            DispatcherType::Custom(dispatch) => dispatch
+        }
+    }
+}
 
 
+/// Control handle of a running stage dispatcher
+///
+/// add or remove subscribers at runtime,
+/// e.g. scale workers up / down or attach a debug tap
+pub struct DispatcherHandle<Out> {
+    control: mpsc::UnboundedSender<Control<Out>>
+}
 
-    
-    #[inline]
-    async fn broadcast(&mut self, events: Vec<Out>) {
-        for index in 0..(self.subscribe_to.len() - 1) {
-            let msg = events.clone();
-            let _ = self.subscribe_to[index].send(msg).await;
+
+impl<Out> Clone for DispatcherHandle<Out> {
+    fn clone(&self) -> Self {
+        DispatcherHandle {
+            control: self.control.clone()
         }
+    }
+}
+
 
-        let _ = self.subscribe_to[self.subscribe_to.len() - 1].send(events).await;
+impl<Out> DispatcherHandle<Out> {
 
+    /// add subscriber to dispatcher
+    ///
+    /// return Err(SendersRepetive) if already subscribed
+    pub async fn subscribe(&self, subscription: Subscription<Out>) -> Result<(), Status> {
+        let (reply, recv) = oneshot::channel();
+        self.request(Control::Subscribe(subscription, reply), recv).await
     }
 
+    /// remove subscriber from dispatcher
+    ///
+    /// return Err(SenderNotFound) if not subscribed,
+    /// Err(LastSender) if it is last subscriber
+    pub async fn unsubscribe(&self, subscription: Subscription<Out>) -> Result<(), Status> {
+        let (reply, recv) = oneshot::channel();
+        self.request(Control::Unsubscribe(subscription, reply), recv).await
+    }
 
-    /// send events to next destination
-    /// 
-    /// roundrobin is safe if a destination terminate
-    /// auto detect it and remove from destinations 
-    #[inline]
-    async fn roundrobin(&mut self, mut events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
-        let mut reason = Ok(());
-        
+    async fn request(&self,
+                     control: Control<Out>,
+                     recv: oneshot::Receiver<Result<(), Status>>) -> Result<(), Status> {
 
-        // ------------------------------------------
-        //
-        //  almost always a destination must never 
-        //   closed unless want shutdown earth  or terminate a flow 
-        //   this service,
-        // 
-        //     with this imagine, almost always
-        //     this fn done with First try
-        //
-        //     so tried to prevent loop instruction for performance
-        //
-        // ------------------------------------------
+        if self.control.send(control).is_err() {
+            return Err(Status::StageStopped)
+        }
 
+        match recv.await {
+            Ok(res) => res,
+            Err(_) => Err(Status::StageStopped)
+        }
+    }
+}
 
 
+enum Control<Out> {
+    Subscribe(Subscription<Out>, oneshot::Sender<Result<(), Status>>),
+    Unsubscribe(Subscription<Out>, oneshot::Sender<Result<(), Status>>)
+}
 
-        // --------------First Try--------------------
 
-        // get next index
-        let mut index = self.next_index();
 
-        // send events 
-        match self.subscribe_to[index].send(events).await {
-            
-            // sending was successful 
-            Ok(_ok) => {
 
-                // return Ok() 
-                return reason
-            }
-            
-            // channel closed
-            Err(err) => {
+/// Dispatcher of a running stage, route events by its Dispatch
+/// and apply subscribe / unsubscribe requests of DispatcherHandle
+struct Dispatcher<Out> {
+    dispatch: Box<dyn Dispatch<Out>>,
 
-                // take ownership of events
-                events = err.0;
+    // subscribe / unsubscribe requests from DispatcherHandle
+    control: mpsc::UnboundedReceiver<Control<Out>>,
+    control_sx: mpsc::UnboundedSender<Control<Out>>,
 
-                // remove this sender from subscribe_to
-                self.subscribe_to.remove(index);
-                
-                // if not exist destination return Err
-                if self.subscribe_to.len() == 0 {
+    // set by supervisor, when not exist any subscriber wait
+    //  this long for restarted one subscribe before fail
+    grace: Option<Duration>
+}
 
-                    reason = Err(DestinationDown(events));
-                    return reason
-                }
-            }
+impl<Out> Dispatcher<Out>
+where
+    Out: Send + 'static
+{
+
+
+    pub fn new(subscribe_to: Vec<Subscription<Out>>,
+               dispatcher_type: DispatcherType<Out>) -> Result<Self, Status> {
+
+        let mut dispatch = dispatcher_type.into_dispatch();
+
+        // Check destinations to not be repetive
+        for sub in subscribe_to {
+            dispatch.subscribe(sub)?;
         }
-        
-        
-        // when First try fail then 
-        //  it run a loop for other destination try 
-        //
-        // -------------- Loop Try --------------------
-        
+
+        let (control_sx, control) = mpsc::unbounded_channel();
+
+        Ok(Dispatcher {
+            dispatch,
+            control,
+            control_sx,
+            grace: None
+        })
+    }
+
+
+    /// stage supervised, its subscribers restarted and
+    /// subscribed again by supervisor when they failed
+    pub(crate) fn supervise(&mut self, grace: Duration) {
+        self.grace = Some(grace);
+    }
+
+
+    /// return control handle to subscribe / unsubscribe at runtime
+    pub fn handle(&self) -> DispatcherHandle<Out> {
+        DispatcherHandle {
+            control: self.control_sx.clone()
+        }
+    }
+
+
+    /// wait until subscribers ask for events,
+    /// apply control requests meanwhile
+    ///
+    /// return demand can dispatch now, see `Dispatch::wait_demand`
+    ///
+    /// return Err if not exist any subscriber to ask,
+    /// when supervised after grace period
+    pub async fn wait_demand(&mut self) -> Result<usize, DestinationDown<Out>> {
         loop {
-            // get next index
-            index = self.next_index();
 
-            // send events 
-            match self.subscribe_to[index].send(events).await {
-                
-                // sending was successful 
-                Ok(_ok) => {
+            self.apply_pending();
 
-                    // return Ok() 
-                    return reason
-                }
-                
-                // channel closed
-                Err(err) => {
-
-                    // take ownership of events
-                    events = err.0;
-
-                    // remove this sender from subscribe_to
-                    self.subscribe_to.remove(index);
-                    
-                    
-                    // if not exist destination return Err
-                    if self.subscribe_to.len() == 0 {
-
-                        reason = Err(DestinationDown(events));
-                        return reason
-                    }
+            let demand = tokio::select! {
+                demand = self.dispatch.wait_demand() => demand,
+                Some(control) = self.control.recv() => {
+                    self.apply(control);
+                    continue;
                 }
+            };
+
+            if demand.is_err() && self.resubscribed().await {
+                continue;
             }
+
+            return demand
         }
     }
 
 
+    /// not exist any running subscriber, when supervised wait
+    /// grace period for restarted subscriber subscribe again
+    ///
+    /// return true if a running subscriber exist now
+    async fn resubscribed(&mut self) -> bool {
+        let Some(grace) = self.grace else {
+            return false
+        };
 
+        // dispatcher failed while subscribers running, e.g. BroadcastStrict
+        if self.running() {
+            return false
+        }
 
+        let deadline = tokio::time::Instant::now() + grace;
 
-    fn next_index(&mut self) -> usize {
-        let mut index = self.c;
+        while let Ok(Some(control)) = tokio::time::timeout_at(deadline, self.control.recv()).await {
+            self.apply(control);
 
-        self.c += 1;
+            if self.running() {
+                return true
+            }
+        }
+
+        false
+    }
+
+
+    fn running(&mut self) -> bool {
+        self.dispatch.subscribers().iter().any(|sub| !sub.is_closed())
+    }
 
-        if index >= self.subscribe_to.len() {
-            self.c = 0;
-            index = 0;
+
+    /// apply subscribe / unsubscribe request
+    fn apply(&mut self, control: Control<Out>) {
+        match control {
+            Control::Subscribe(sub, reply) => {
+                let _ = reply.send(self.dispatch.subscribe(sub));
+            }
+            Control::Unsubscribe(sub, reply) => {
+                let _ = reply.send(self.dispatch.unsubscribe(&sub));
+            }
         }
+    }
+
 
-        return index
+    /// apply all received control requests, without wait
+    fn apply_pending(&mut self) {
+        while let Ok(control) = self.control.try_recv() {
+            self.apply(control);
+        }
     }
 
-    
-    /// Check destinations to not be repetive
-    fn check(subscribe_to: &Vec<Sender<Vec<Out>>>) -> Result<(), ()> {
-        for (oindex, outer_dst) in subscribe_to.iter().enumerate() {
-            for (iindex, inner_dst) in subscribe_to.iter().enumerate() {
-            
-                // if not was itself && channel was same
-                if oindex != iindex && outer_dst.same_channel(inner_dst) {
-                    return Err(())
+
+    /// dispatch all events to subscribers,
+    /// never send more than subscribers demand,
+    /// if not exist demand wait for it
+    ///
+    /// events given to Dispatch in chunks of demand,
+    /// control requests applied between chunks
+    #[inline]
+    pub async fn dispatch(&mut self, mut events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
+        while !events.is_empty() {
+
+            let demand = match self.wait_demand().await {
+                Ok(demand) => demand,
+                Err(_) => return Err(DestinationDown(events))
+            };
+
+            let rest = events.split_off(demand.min(events.len()));
+
+            if let Err(DestinationDown(mut unsent)) = self.dispatch.dispatch(events).await {
+                unsent.extend(rest);
+
+                // dispatch again to restarted subscriber
+                if self.resubscribed().await {
+                    events = unsent;
+                    continue;
                 }
-            
+
+                return Err(DestinationDown(unsent))
             }
+
+            events = rest;
         }
 
         Ok(())
     }
 }
-
-
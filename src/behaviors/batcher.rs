@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use super::producer_consumer::ProducerConsumer;
+
+
+
+/// Events grouped by Batcher
+#[derive(Debug)]
+pub struct Batch<K, T> {
+    pub key    : K,
+    pub events : Vec<T>
+}
+
+
+
+/// Built-in ProducerConsumer, accumulate events until batch_size reached
+/// or batch_timeout elapsed from first event of batch, then send
+/// batch to next stage, optionally grouped by key
+///
+/// held batches sent when upstream closed
+///
+/// ```rust,ignore
+/// // Producer -> Batcher -> Consumer<Batch<(), Event>>
+/// let (batcher_chan, _, _) = ProducerConsumerRunnable::new(Box::new(Batcher::new(100, Duration::from_secs(1))),
+///                                                         vec![db_chan],
+///                                                         None)
+///                             .unwrap()
+///                             .run(100);
+/// ```
+pub struct Batcher<In, K = ()> {
+    batch_size    : usize,
+    batch_timeout : Duration,
+    key           : Box<dyn Fn(&In) -> K + Send + Sync>,
+
+    // key -> (batch first event time, events)
+    pending       : HashMap<K, (Instant, Vec<In>)>
+}
+
+
+
+impl<In> Batcher<In, ()> {
+
+    /// batch_size zero is treated as one
+    pub fn new(batch_size: usize, batch_timeout: Duration) -> Self {
+        Batcher::by_key(batch_size, batch_timeout, |_| ())
+    }
+}
+
+
+impl<In, K> Batcher<In, K>
+where
+    K: Hash + Eq + Clone
+{
+    /// every key has its own batch
+    pub fn by_key<F>(batch_size: usize, batch_timeout: Duration, key: F) -> Self
+    where
+        F: Fn(&In) -> K + Send + Sync + 'static
+    {
+        Batcher {
+            batch_size: batch_size.max(1),
+            batch_timeout,
+            key: Box::new(key),
+            pending: HashMap::new()
+        }
+    }
+
+
+    /// take batches which timeout elapsed, or all if now is None
+    fn take_expired(&mut self, now: Option<Instant>) -> Vec<Batch<K, In>> {
+        let timeout = self.batch_timeout;
+
+        let keys: Vec<K> = self.pending
+            .iter()
+            .filter(|(_, (start, _))| now.is_none_or(|now| *start + timeout <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|key| {
+                self.pending
+                    .remove(&key)
+                    .map(|(_, events)| Batch { key, events })
+            })
+            .collect()
+    }
+}
+
+
+
+#[async_trait]
+impl<In, K> ProducerConsumer<In, Batch<K, In>> for Batcher<In, K>
+where
+    In: Send,
+    K: Hash + Eq + Clone + Send
+{
+    type Error = Infallible;
+
+    async fn init(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, upstream_events: Vec<In>) -> Result<Vec<Batch<K, In>>, Infallible> {
+        let mut full = Vec::new();
+
+        for event in upstream_events {
+            let key = (self.key)(&event);
+
+            let (_, events) = self.pending
+                .entry(key.clone())
+                .or_insert_with(|| (Instant::now(), Vec::with_capacity(self.batch_size)));
+
+            events.push(event);
+
+            if events.len() >= self.batch_size {
+                if let Some((_, events)) = self.pending.remove(&key) {
+                    full.push(Batch { key, events })
+                }
+            }
+        }
+
+        Ok(full)
+    }
+
+    async fn terminate(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+
+    fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|(start, _)| *start + self.batch_timeout)
+            .min()
+    }
+
+    async fn handle_deadline(&mut self) -> Result<Vec<Batch<K, In>>, Infallible> {
+        Ok(self.take_expired(Some(Instant::now())))
+    }
+
+    async fn drain(&mut self) -> Result<Vec<Batch<K, In>>, Infallible> {
+        Ok(self.take_expired(None))
+    }
+}
@@ -1,10 +1,19 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-
-use tokio::sync::mpsc::Sender;
 use tokio::sync::mpsc::channel;
 use async_trait::async_trait;
 
-use super::DestinationDown;
+use crate::Status;
+
+use super::stage::{self, ExitReason, StageHandle};
+use super::error::{OnError, StageError};
+use super::dead_letter::{self, DeadLetterTo, Failed};
+use super::retry::{Retry, Retrying};
+use super::metrics::{self, Metric, Metrics};
+use super::trace::{self, event, Traced};
+use super::shutdown::{Shutdown, ShutdownToken};
+use super::subscription::{self, Asker, Demand, Subscription};
 
 pub enum State<ConsumerIn> {
     Continue,
@@ -15,75 +24,344 @@ pub enum State<ConsumerIn> {
 
 #[async_trait]
 pub trait Consumer<ConsumerIn> {
-    
-    /// init used for initialize producer
-    async fn init(&mut self);
+
+    /// error returned by callbacks
+    type Error: Send;
+
+    /// init used for initialize producer,
+    /// if failed consumer never start
+    async fn init(&mut self) -> Result<(), Self::Error>;
 
     /// receive events from upstream and cunsome it
-    async fn handle_events(&mut self, upstream_events: Vec<ConsumerIn>) -> State<ConsumerIn>;
+    async fn handle_events(&mut self, upstream_events: Vec<ConsumerIn>) -> Result<State<ConsumerIn>, Self::Error>;
+
+
+    async fn terminate(&mut self) -> Result<(), Self::Error>;
 
 
-    async fn terminate(&mut self);
+    /// called every tick interval set by `with_tick`
+    async fn handle_tick(&mut self) -> Result<State<ConsumerIn>, Self::Error> {
+        Ok(State::Continue)
+    }
+
+    /// one-off timer, when runner call handle_deadline,
+    /// asked again after every callback, default never
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// called when deadline reached
+    async fn handle_deadline(&mut self) -> Result<State<ConsumerIn>, Self::Error> {
+        Ok(State::Continue)
+    }
+
+    /// events failed by last callback with their reason,
+    /// runner take them after every callback and send to dead letter
+    fn take_failed(&mut self) -> Vec<Failed<ConsumerIn>> {
+        Vec::new()
+    }
 }
 
 // -----------------------------------------
 
-pub struct ConsumerRunnable<ConsumerIn> {
-    proc         : Box<dyn Consumer<ConsumerIn> + Send>
+pub struct ConsumerRunnable<ConsumerIn, E> {
+    name         : String,
+    proc         : Box<dyn Consumer<ConsumerIn, Error = E> + Send>,
+    on_error     : OnError<E>,
+    metrics      : Option<Arc<dyn Metrics>>,
+    dead_letter  : Option<DeadLetterTo<ConsumerIn>>,
+    retry        : Option<Retrying<ConsumerIn, E>>,
+
+    min_demand   : usize,
+    max_demand   : usize,
+    tick         : Option<Duration>,
+
+    // dropped when stage stopped
+    token        : Option<ShutdownToken>
 }
 
 
-impl<ConsumerIn> ConsumerRunnable<ConsumerIn> 
+impl<ConsumerIn, E> ConsumerRunnable<ConsumerIn, E>
 where
-    ConsumerIn:  Clone + Send + 'static
+    ConsumerIn:  Send + 'static,
+    E:           Send + 'static
 {
-    pub fn new(proc: Box<dyn Consumer<ConsumerIn> + Send> ) -> Self {
+    pub fn new(proc: Box<dyn Consumer<ConsumerIn, Error = E> + Send> ) -> Self {
         ConsumerRunnable {
-            proc
+            name: String::from("consumer"),
+            proc,
+            on_error: OnError::Stop,
+            metrics: None,
+            dead_letter: None,
+            retry: None,
+            min_demand: subscription::DEFAULT_MIN_DEMAND,
+            max_demand: subscription::DEFAULT_MAX_DEMAND,
+            tick: None,
+            token: None
+        }
+    }
+
+
+    /// set demand asked from upstream
+    ///
+    /// first ask max_demand events, then every time
+    /// outstanding demand fall to min_demand ask again up to max_demand
+    pub fn with_demand(mut self, min_demand: usize, max_demand: usize) -> Result<Self, Status> {
+        if !subscription::check_demand(min_demand, max_demand) {
+            return Err(Status::InvalidDemand);
+        }
+
+        self.min_demand = min_demand;
+        self.max_demand = max_demand;
+        Ok(self)
+    }
+
+
+    /// name of stage, default is its kind
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+
+    /// track stage by pipeline shutdown, `Shutdown::shutdown`
+    /// wait until this stage drained its channel and terminated
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.token = Some(shutdown.token());
+        self
+    }
+
+
+    /// record stage metrics to sink
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+
+    /// send events returned from take_failed to destination,
+    /// without it failed events dropped
+    pub fn with_dead_letter(mut self, to: impl Into<DeadLetterTo<ConsumerIn>>) -> Self {
+        self.dead_letter = Some(to.into());
+        self
+    }
+
+
+    /// call handle_tick every interval, even when no events arrive
+    pub fn with_tick(mut self, interval: Duration) -> Self {
+        self.tick = Some(interval);
+        self
+    }
+
+
+    /// set what to do when handle_events failed, default OnError::Stop
+    pub fn with_on_error(mut self, on_error: OnError<E>) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+
+    /// call handle_events, again by Retry policy while it failed,
+    /// return result of last attempt and events failed for good
+    async fn handle_events(&mut self, upstream_events: Vec<ConsumerIn>) -> (Result<State<ConsumerIn>, E>, Vec<Failed<ConsumerIn>>) {
+        let mut batch = upstream_events;
+        let mut attempt = 1;
+
+        // failed events not retried anymore
+        let mut dead = Vec::new();
+
+        let res = loop {
+            let copy = self.retry.as_ref().and_then(|retry| retry.copy(&batch, attempt));
+
+            let len = batch.len();
+            let start = Instant::now();
+            let res = self.proc.handle_events(batch).in_call("handle_events", len).await;
+            metrics::record(&self.metrics, &self.name, Metric::Handler(start.elapsed()));
+
+            let mut failed = self.proc.take_failed();
+
+            let Some(retry) = self.retry.as_ref().filter(|retry| retry.policy.again(attempt)) else {
+                dead.append(&mut failed);
+                break res
+            };
+
+            batch = match (res, copy) {
+
+                // whole batch again, its per-event failures dropped
+                (Err(err), Some(copy)) if retry.policy.is_retryable(&err) => copy,
+
+                // only failed subset again
+                (Ok(State::Continue), _) if failed.iter().any(|failed| failed.retryable) => {
+                    let (again, mut fatal): (Vec<_>, Vec<_>) = failed
+                        .into_iter()
+                        .partition(|failed| failed.retryable);
+
+                    dead.append(&mut fatal);
+                    again.into_iter().map(|failed| failed.event).collect()
+                }
+
+                (res, _) => {
+                    dead.append(&mut failed);
+                    break res
+                }
+            };
+
+            event!(warn, attempt, "retry");
+
+            tokio::time::sleep(retry.policy.delay(attempt)).await;
+            attempt += 1;
+        };
+
+        (res, dead)
+    }
+
+
+    /// record failed events and send them to dead letter
+    async fn dead_letter(&mut self, failed: Vec<Failed<ConsumerIn>>) {
+        if failed.is_empty() {
+            return
         }
+
+        metrics::record(&self.metrics, &self.name, Metric::Failed(failed.len()));
+        dead_letter::route(&mut self.dead_letter, &self.name, failed).await;
     }
 
 
+    /// run stage, return subscription for upstream
+    /// and stage handle to await its ExitReason
     #[inline]
-    pub fn run(mut self, buffer: usize) -> Sender<Vec<ConsumerIn>> {
+    pub fn run(mut self, buffer: usize) -> (Subscription<ConsumerIn>, StageHandle<ConsumerIn, E>) {
+
+        let (sx, mut rx) = channel::<Vec<ConsumerIn>>(buffer);
+        let demand = Arc::new(Demand::new());
+
+        let mut asker = Asker::new(demand.clone(), self.min_demand, self.max_demand);
+
+        let subscription = Subscription::new(sx, demand);
+        let counter = subscription.counter();
 
-        let (sx, mut rx) = channel(buffer);
+        let name = self.name.clone();
+        let token = self.token.clone();
 
-        tokio::spawn(async move {
-            
-            self.proc.init().await;
+        let stage = async move {
+
+            if let Err(err) = self.proc.init().await {
+                return ExitReason::Failed(StageError::Init(err))
+            }
+
+            event!(info, "init");
+
+            let mut ticker = stage::ticker(self.tick);
+
+            // consumer returned State::Terminate
+            let mut terminated = false;
 
             loop {
-            
-                // Listen on channel
-                match rx.recv().await {
-                    Some(upstream_events) => {
 
-                        // produce events and dispatch
-                        match self.proc.handle_events(upstream_events).await {
-                            State::Continue => (),
-                            State::Terminate => {
+                // Listen on channel, until tick or stage deadline
+                let deadline = self.proc.deadline();
+
+                // callback result, events failed for good and events consumed,
+                // None if upstream terminate
+                let handled = tokio::select! {
+                    recv = rx.recv() => match recv {
+                        Some(upstream_events) => {
+                            let len = subscription::count(&counter, &upstream_events);
 
-                                // close channel to not get anymore 
+                            metrics::record(&self.metrics, &self.name, Metric::EventsIn(len));
+                            metrics::record(&self.metrics, &self.name, Metric::Batch(len));
+                            metrics::record(&self.metrics, &self.name, Metric::Queue { len: rx.len(), capacity: buffer });
+
+                            let (res, failed) = self.handle_events(upstream_events).await;
+
+                            Some((res, failed, len))
+                        }
+                        None => None
+                    },
+                    _ = stage::next_tick(&mut ticker), if !terminated => {
+                        let res = self.proc.handle_tick().in_call("handle_tick", 0).await;
+                        Some((res, self.proc.take_failed(), 0))
+                    }
+                    _ = stage::sleep_until(deadline), if !terminated => {
+                        let res = self.proc.handle_deadline().in_call("handle_deadline", 0).await;
+                        Some((res, self.proc.take_failed(), 0))
+                    }
+                };
+
+                match handled {
+                    Some((res, failed, len)) => {
+
+                        self.dead_letter(failed).await;
+
+                        match res {
+                            Ok(State::Continue) => (),
+                            Ok(State::Terminate) => {
+
+                                // close channel to not get anymore
                                 rx.close();
+                                terminated = true;
+                            }
+                            Ok(State::DestinationDown(events)) => {
+                                return ExitReason::DestinationDown(events)
                             }
-                            State::DestinationDown(events) => {
-                                return Some(DestinationDown(events))
+                            Err(err) => {
+                                event!(warn, "callback failed");
+
+                                if let Err(err) = self.on_error.apply(StageError::Handle(err)) {
+                                    return ExitReason::Failed(err)
+                                }
                             }
-                        }              
-                        
+                        }
+
+                        // events consumed, ask more from upstream
+                        asker.consumed(len);
                     }
                     None => {
                         // upstream terminate
-                        self.proc.terminate().await;
-                        return None
+                        if let Err(err) = self.proc.terminate().await {
+                            return ExitReason::Failed(StageError::Terminate(err))
+                        }
+
+                        event!(info, "terminate");
+
+                        if terminated {
+                            return ExitReason::Normal
+                        }
+
+                        return ExitReason::UpstreamClosed
                     }
                 }
-  
+
             }
-        });
+        };
+
+        let join = tokio::spawn(trace::exited(stage).in_stage(&name, "consumer"));
+
+        if let Some(token) = token {
+            token.track(join.abort_handle());
+        }
 
-        sx
+        (subscription, StageHandle::new(join))
     }
 }
 
+
+
+impl<ConsumerIn, E> ConsumerRunnable<ConsumerIn, E>
+where
+    ConsumerIn:  Clone + Send + 'static,
+    E:           Send + 'static
+{
+    /// call handle_events again by policy when it failed, see `Retry`
+    ///
+    /// every batch cloned before every attempt except last one,
+    /// also when attempt succeed, events must be Clone
+    pub fn with_retry(mut self, retry: Retry<E>) -> Self {
+        self.retry = Some(Retrying::new(retry));
+        self
+    }
+}
@@ -0,0 +1,126 @@
+use tokio::sync::mpsc::Sender;
+
+use super::dispatch::Subscribers;
+use super::subscription::Subscription;
+use super::trace::event;
+
+
+
+/// Event a stage failed to process, returned from `take_failed`
+pub struct Failed<T> {
+    pub event     : T,
+    pub reason    : String,
+
+    /// retried by runner Retry policy before sent to dead letter
+    pub retryable : bool
+}
+
+
+impl<T> Failed<T> {
+
+    /// retryable failure
+    pub fn new(event: T, reason: impl Into<String>) -> Self {
+        Failed {
+            event,
+            reason: reason.into(),
+            retryable: true
+        }
+    }
+
+    /// failure never retried, sent to dead letter directly
+    pub fn fatal(event: T, reason: impl Into<String>) -> Self {
+        Failed {
+            event,
+            reason: reason.into(),
+            retryable: false
+        }
+    }
+}
+
+
+
+/// Failed event sent to dead letter destination,
+/// with name of stage it failed in
+pub struct DeadLetter<T> {
+    pub event  : T,
+    pub reason : String,
+    pub stage  : String
+}
+
+
+
+/// Destination of failed events, set by runnables `with_dead_letter`
+///
+/// ```rust,ignore
+/// let (dead_chan, _) = ConsumerRunnable::new(Box::new(LogDead)).run(100);
+///
+/// let (log_chan, _) = ConsumerRunnable::new(Box::new(Log))
+///                     .with_dead_letter(dead_chan)
+///                     .run(100);
+/// ```
+pub enum DeadLetterTo<T> {
+
+    /// Consumer stage, subscription returned from its run,
+    /// failed events sent by its demand like any upstream
+    Stage(Subscription<DeadLetter<T>>),
+
+    /// any channel
+    Sender(Sender<Vec<DeadLetter<T>>>)
+}
+
+
+impl<T> From<Subscription<DeadLetter<T>>> for DeadLetterTo<T> {
+    fn from(subscription: Subscription<DeadLetter<T>>) -> Self {
+        DeadLetterTo::Stage(subscription)
+    }
+}
+
+
+impl<T> From<Sender<Vec<DeadLetter<T>>>> for DeadLetterTo<T> {
+    fn from(sender: Sender<Vec<DeadLetter<T>>>) -> Self {
+        DeadLetterTo::Sender(sender)
+    }
+}
+
+
+
+/// send failed events of stage to dead letter destination
+///
+/// failed events dropped if no destination set or it stopped,
+/// stage never stop because of its dead letter, but wait
+/// while dead letter stage has no demand
+pub(crate) async fn route<T>(to: &mut Option<DeadLetterTo<T>>, stage: &str, failed: Vec<Failed<T>>) {
+    if failed.is_empty() {
+        return
+    }
+
+    let letters: Vec<DeadLetter<T>> = failed
+        .into_iter()
+        .map(|failed| DeadLetter {
+            event: failed.event,
+            reason: failed.reason,
+            stage: stage.to_string()
+        })
+        .collect();
+
+    let sent = match to {
+        Some(DeadLetterTo::Stage(subscription)) => {
+
+            // wait for demand of stage, never send more than it asked
+            let mut subscribers = Subscribers::new();
+            let _ = subscribers.add(subscription.clone());
+
+            subscribers.send(0, letters).await.is_ok()
+        }
+        Some(DeadLetterTo::Sender(sender)) => sender.send(letters).await.is_ok(),
+        None => {
+            event!(warn, dropped = letters.len(), "failed events dropped");
+            return
+        }
+    };
+
+    if !sent {
+        event!(warn, "dead letter down");
+        *to = None;
+    }
+}
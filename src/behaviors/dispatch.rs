@@ -0,0 +1,290 @@
+use std::ops::Index;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::Notify;
+
+use crate::Status;
+
+use super::DestinationDown;
+use super::subscription::Subscription;
+
+
+
+/// Routing of events from a stage to its subscribers
+///
+/// built-in modes of DispatcherType implement it, custom one
+/// given to runnables by `DispatcherType::custom`
+///
+/// contract of implementations:
+///  - dispatch send every event, or return Err(DestinationDown)
+///    with events not sent, then stage stop with
+///    ExitReason::DestinationDown of these events
+///  - never send subscriber more than its demand,
+///    take it by `Subscription::take_demand` before send
+///  - stopped subscribers removed, when not exist any
+///    subscriber return Err
+///  - wait_demand must be cancel safe, runner stop waiting
+///    on it when shutdown or subscribe request arrive
+///
+/// ```rust,ignore
+/// // route events of every tenant to its own worker
+/// struct ByTenant {
+///     subscribers: Subscribers<Job>
+/// }
+///
+/// #[async_trait]
+/// impl Dispatch<Job> for ByTenant {
+///
+///     fn subscribers(&mut self) -> &mut Subscribers<Job> {
+///         &mut self.subscribers
+///     }
+///
+///     async fn dispatch(&mut self, events: Vec<Job>) -> Result<(), DestinationDown<Job>> {
+///         let mut events = events.into_iter();
+///
+///         while let Some(job) = events.next() {
+///             if self.subscribers.is_empty() {
+///                 return Err(DestinationDown(std::iter::once(job).chain(events).collect()))
+///             }
+///
+///             let index = job.tenant % self.subscribers.len();
+///
+///             // subscriber stopped, return job and rest of events
+///             if let Err(mut dd) = self.subscribers.send(index, vec![job]).await {
+///                 dd.0.extend(events);
+///                 return Err(dd)
+///             }
+///         }
+///
+///         Ok(())
+///     }
+/// }
+///
+/// let (_producer, _) = ProducerRunnable::new(Box::new(Jobs),
+///                                            workers,
+///                                            Some(DispatcherType::custom(ByTenant { subscribers: Subscribers::new() })),
+///                                            100,
+///                                            shutdown.producer()).unwrap().run();
+/// ```
+#[async_trait]
+pub trait Dispatch<Out: Send>: Send {
+
+    /// subscribers of dispatcher
+    fn subscribers(&mut self) -> &mut Subscribers<Out>;
+
+    /// name of dispatcher mode, shown in topology
+    fn name(&self) -> &'static str {
+        "Custom"
+    }
+
+    /// subscriber added, when runnable created or by DispatcherHandle,
+    /// return Err(SendersRepetive) if already subscribed
+    fn subscribe(&mut self, subscription: Subscription<Out>) -> Result<(), Status> {
+        self.subscribers().add(subscription)
+    }
+
+    /// subscriber removed by DispatcherHandle,
+    /// return Err(SenderNotFound) / Err(LastSender)
+    fn unsubscribe(&mut self, subscription: &Subscription<Out>) -> Result<(), Status> {
+        self.subscribers().remove(subscription).map(|_| ())
+    }
+
+    /// wait until subscribers ask for events, return demand
+    /// can dispatch now, default sum of subscribers demand
+    ///
+    /// return Err if not exist any subscriber to ask
+    async fn wait_demand(&mut self) -> Result<usize, DestinationDown<Out>> {
+        let subscribers = self.subscribers();
+
+        loop {
+            subscribers.remove_closed();
+
+            if subscribers.is_empty() {
+                return Err(DestinationDown(Vec::new()))
+            }
+
+            let demand = subscribers.demand_sum();
+            if demand > 0 {
+                return Ok(demand)
+            }
+
+            subscribers.wait().await;
+        }
+    }
+
+    /// send events to subscribers
+    async fn dispatch(&mut self, events: Vec<Out>) -> Result<(), DestinationDown<Out>>;
+}
+
+
+
+
+/// Subscribers of a dispatcher, notify it when
+/// any subscriber ask demand or stopped
+pub struct Subscribers<Out> {
+    list  : Vec<Subscription<Out>>,
+    waker : Arc<Notify>
+}
+
+
+impl<Out> Default for Subscribers<Out> {
+    fn default() -> Self {
+        Subscribers {
+            list: Vec::new(),
+            waker: Arc::new(Notify::new())
+        }
+    }
+}
+
+
+impl<Out> Subscribers<Out> {
+
+    pub fn new() -> Self {
+        Subscribers::default()
+    }
+
+    /// return Err(SendersRepetive) if already subscribed
+    pub fn add(&mut self, subscription: Subscription<Out>) -> Result<(), Status> {
+        if self.list.iter().any(|sub| sub.same_channel(&subscription)) {
+            return Err(Status::SendersRepetive)
+        }
+
+        subscription.demand().register(&self.waker);
+        self.list.push(subscription);
+        Ok(())
+    }
+
+    /// return Err(SenderNotFound) if not subscribed,
+    /// Err(LastSender) if it is last subscriber
+    pub fn remove(&mut self, subscription: &Subscription<Out>) -> Result<Subscription<Out>, Status> {
+        let index = match self.list.iter().position(|sub| sub.same_channel(subscription)) {
+            Some(index) => index,
+            None => return Err(Status::SenderNotFound)
+        };
+
+        if self.list.len() == 1 {
+            return Err(Status::LastSender)
+        }
+
+        Ok(self.remove_at(index))
+    }
+
+    pub fn remove_at(&mut self, index: usize) -> Subscription<Out> {
+        let sub = self.list.remove(index);
+        sub.demand().unregister(&self.waker);
+        sub
+    }
+
+    /// remove stopped subscribers, return them
+    pub fn remove_closed(&mut self) -> Vec<Subscription<Out>> {
+        let mut closed = Vec::new();
+
+        let mut index = 0;
+        while index < self.list.len() {
+            if self.list[index].is_closed() {
+                closed.push(self.remove_at(index));
+            } else {
+                index += 1;
+            }
+        }
+
+        closed
+    }
+
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&Subscription<Out>> {
+        self.list.get(index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Subscription<Out>> {
+        self.list.iter()
+    }
+
+    /// index of subscriber by `Subscription::id`
+    pub fn position(&self, id: u64) -> Option<usize> {
+        self.list.iter().position(|sub| sub.id() == id)
+    }
+
+
+    /// sum of subscribers demand
+    pub fn demand_sum(&self) -> usize {
+        self.list.iter().map(|sub| sub.pending_demand()).sum()
+    }
+
+    /// minimum of subscribers demand
+    pub fn demand_min(&self) -> usize {
+        self.list.iter().map(|sub| sub.pending_demand()).min().unwrap_or(0)
+    }
+
+
+    /// wait until a subscriber ask demand or stopped
+    pub async fn wait(&self) {
+        self.waker.notified().await
+    }
+
+
+    /// send all events to subscriber at index, wait for its demand
+    ///
+    /// if subscriber stopped it removed and
+    /// return Err with events not sent
+    pub async fn send(&mut self, index: usize, mut events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
+        while !events.is_empty() {
+
+            if self.list[index].is_closed() {
+                self.remove_at(index);
+                return Err(DestinationDown(events))
+            }
+
+            // take demand of subscriber, if not exist wait for it
+            let taken = self.list[index].take_demand(events.len());
+            if taken == 0 {
+                self.wait().await;
+                continue;
+            }
+
+            let rest = events.split_off(taken);
+
+            if let Err(err) = self.list[index].send(events).await {
+                events = err.0;
+                events.extend(rest);
+
+                self.remove_at(index);
+                return Err(DestinationDown(events))
+            }
+
+            events = rest;
+        }
+
+        Ok(())
+    }
+}
+
+
+impl<Out> Index<usize> for Subscribers<Out> {
+    type Output = Subscription<Out>;
+
+    fn index(&self, index: usize) -> &Subscription<Out> {
+        &self.list[index]
+    }
+}
+
+
+impl<Out> Drop for Subscribers<Out> {
+    fn drop(&mut self) {
+        for sub in self.list.iter() {
+            sub.demand().unregister(&self.waker);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+/// Error returned by a stage callback
+#[derive(Debug)]
+pub enum StageError<E> {
+
+    /// init failed, stage never started
+    Init(E),
+
+    /// handle_demand / handle_events failed
+    Handle(E),
+
+    /// terminate failed
+    Terminate(E)
+}
+
+
+
+
+/// What runner do when handle_demand / handle_events failed
+///
+/// init or terminate failure always stop stage
+/// with ExitReason::Failed
+pub enum OnError<E> {
+
+    /// stop stage, ExitReason::Failed
+    Stop,
+
+    /// skip batch and continue
+    Skip,
+
+    /// route error to handler, then skip batch and continue
+    Handle(Box<dyn FnMut(StageError<E>) + Send>)
+}
+
+
+impl<E> OnError<E> {
+
+    /// return Err if stage must stop
+    pub(crate) fn apply(&mut self, err: StageError<E>) -> Result<(), StageError<E>> {
+        match self {
+            OnError::Stop => Err(err),
+            OnError::Skip => Ok(()),
+            OnError::Handle(handler) => {
+                handler(err);
+                Ok(())
+            }
+        }
+    }
+}
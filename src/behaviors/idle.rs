@@ -0,0 +1,149 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+
+
+/// What producer runner do when handle_demand returned no events
+///
+/// empty batches never dispatched
+pub enum Idle {
+
+    /// call handle_demand again immediately
+    Immediate,
+
+    /// wait fixed interval between empty polls
+    Interval(Duration),
+
+    /// wait between empty polls, start from min and double
+    /// after every empty poll up to max, with jitter,
+    /// reset to min when producer returned events
+    Backoff {
+        min: Duration,
+        max: Duration
+    }
+}
+
+
+impl Default for Idle {
+    fn default() -> Self {
+        Idle::Backoff {
+            min: Duration::from_millis(1),
+            max: Duration::from_millis(100)
+        }
+    }
+}
+
+
+
+/// Wake idle producer runner before its wait elapsed
+///
+/// ```rust,ignore
+/// let waker = IdleWaker::new();
+///
+/// let (_producer, _) = ProducerRunnable::new(Box::new(Queue { waker: waker.clone() }), ...)
+///                     .unwrap()
+///                     .with_idle(Idle::Interval(Duration::from_secs(1)), Some(waker))
+///                     .run();
+///
+/// // when data arrived
+/// waker.wake();
+/// ```
+#[derive(Clone, Default)]
+pub struct IdleWaker {
+    notify: Arc<Notify>
+}
+
+
+impl IdleWaker {
+
+    pub fn new() -> Self {
+        IdleWaker {
+            notify: Arc::new(Notify::new())
+        }
+    }
+
+    /// wake runner, if runner is not idle
+    /// next idle wait return immediately
+    pub fn wake(&self) {
+        self.notify.notify_one()
+    }
+}
+
+
+
+
+pub(crate) struct IdleState {
+    idle    : Idle,
+    waker   : Option<IdleWaker>,
+
+    // empty polls in a row
+    empties : u32,
+
+    // last poll was empty, wait before next poll
+    pending : bool
+}
+
+
+impl IdleState {
+
+    pub(crate) fn new(idle: Idle, waker: Option<IdleWaker>) -> Self {
+        IdleState {
+            idle,
+            waker,
+            empties: 0,
+            pending: false
+        }
+    }
+
+
+    /// producer returned events
+    #[inline]
+    pub(crate) fn reset(&mut self) {
+        self.empties = 0;
+    }
+
+    /// producer returned no events
+    #[inline]
+    pub(crate) fn empty(&mut self) {
+        self.pending = !matches!(self.idle, Idle::Immediate);
+    }
+
+    #[inline]
+    pub(crate) fn pending(&self) -> bool {
+        self.pending
+    }
+
+
+    /// wait by Idle or until woken
+    pub(crate) async fn wait(&mut self) {
+        let delay = match self.idle {
+            Idle::Immediate => Duration::ZERO,
+            Idle::Interval(interval) => interval,
+            Idle::Backoff { min, max } => {
+                let delay = min
+                    .saturating_mul(2u32.saturating_pow(self.empties))
+                    .min(max);
+
+                self.empties = self.empties.saturating_add(1);
+
+                // jitter between delay/2 and delay
+                let half = delay / 2;
+                half + half.mul_f64(fastrand::f64())
+            }
+        };
+
+        match &self.waker {
+            Some(waker) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => (),
+                    _ = waker.notify.notified() => ()
+                }
+            }
+            None => tokio::time::sleep(delay).await
+        }
+
+        self.pending = false;
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use async_trait::async_trait;
+
+use super::consumer::{Consumer, State};
+use super::dead_letter::Failed;
+
+
+
+/// Metadata of a message
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub id        : String,
+    pub timestamp : SystemTime,
+    pub headers   : HashMap<String, String>
+}
+
+
+impl Metadata {
+
+    /// timestamp is now
+    pub fn new(id: impl Into<String>) -> Self {
+        Metadata {
+            id: id.into(),
+            timestamp: SystemTime::now(),
+            headers: HashMap::new()
+        }
+    }
+
+    pub fn with_header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(key.into(), value.into());
+        self
+    }
+}
+
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata::new(String::new())
+    }
+}
+
+
+
+/// Called when message settled, implemented by producer source
+/// e.g. delete message from queue on ack, make it visible again on nack
+pub trait Acknowledger: Send + Sync {
+
+    fn ack(&self, metadata: &Metadata);
+
+    fn nack(&self, metadata: &Metadata, reason: &str);
+}
+
+
+
+/// Settle handle of a message, ack or nack it once
+///
+/// dropped without settle is nack with reason "dropped",
+/// so message lost by a stage never acked
+pub struct Ack {
+    metadata     : Metadata,
+    acknowledger : Option<Arc<dyn Acknowledger>>
+}
+
+
+impl Ack {
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn ack(mut self) {
+        if let Some(acknowledger) = self.acknowledger.take() {
+            acknowledger.ack(&self.metadata)
+        }
+    }
+
+    pub fn nack(mut self, reason: &str) {
+        if let Some(acknowledger) = self.acknowledger.take() {
+            acknowledger.nack(&self.metadata, reason)
+        }
+    }
+}
+
+
+impl Drop for Ack {
+    fn drop(&mut self) {
+        if let Some(acknowledger) = self.acknowledger.take() {
+            acknowledger.nack(&self.metadata, "dropped")
+        }
+    }
+}
+
+
+
+/// Event envelope, data with metadata and acknowledger
+/// of producer it came from
+///
+/// flow through stages as any event, `Producer<Message<T>>`,
+/// `ProducerConsumer<Message<T>, Message<U>>` and `Consumer<Message<T>>`
+///
+/// message not Clone, Broadcast dispatchers can not send it
+/// and stages of it can not use `with_retry`
+pub struct Message<T> {
+    pub data : T,
+    ack      : Ack
+}
+
+
+impl<T> Message<T> {
+
+    pub fn new(data: T, metadata: Metadata, acknowledger: Arc<dyn Acknowledger>) -> Self {
+        Message {
+            data,
+            ack: Ack {
+                metadata,
+                acknowledger: Some(acknowledger)
+            }
+        }
+    }
+
+    /// message without acknowledger, ack / nack do nothing
+    pub fn unacked(data: T, metadata: Metadata) -> Self {
+        Message {
+            data,
+            ack: Ack {
+                metadata,
+                acknowledger: None
+            }
+        }
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.ack.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.ack.metadata
+    }
+
+    /// transform data, keep metadata and acknowledger
+    pub fn map<U, F>(self, f: F) -> Message<U>
+    where
+        F: FnOnce(T) -> U
+    {
+        Message {
+            data: f(self.data),
+            ack: self.ack
+        }
+    }
+
+    pub fn into_parts(self) -> (T, Ack) {
+        (self.data, self.ack)
+    }
+
+    pub fn ack(self) {
+        self.ack.ack()
+    }
+
+    pub fn nack(self, reason: &str) {
+        self.ack.nack(reason)
+    }
+}
+
+
+
+/// Consumer of messages, pass data to inner consumer then
+/// ack every message if it returned Ok, nack if it returned Err
+///
+/// events inner consumer reported failed by take_failed matched
+/// to their messages by key, those messages nacked with reason of
+/// failure and returned from take_failed to dead letter, only rest acked
+///
+/// if inner returned DestinationDown, messages nacked and
+/// stranded events dropped
+///
+/// ```rust,ignore
+/// // failed events matched by hash of event
+/// let (sink_chan, _) = ConsumerRunnable::new(Box::new(AckConsumer::new(Log))).run(100);
+///
+/// // or by hash of a key of event
+/// let sink = AckConsumer::keyed(Sqlite, |row: &Row| row.id);
+/// ```
+pub struct AckConsumer<C, T> {
+    inner  : C,
+    key    : Box<dyn Fn(&T) -> u64 + Send + Sync>,
+
+    // failed messages of last batch, already nacked
+    failed : Vec<Failed<Message<T>>>
+}
+
+
+impl<C, T: Hash> AckConsumer<C, T> {
+
+    /// failed events matched to messages by hash of event
+    pub fn new(inner: C) -> Self {
+        AckConsumer::keyed(inner, |event: &T| hash(event))
+    }
+}
+
+
+impl<C, T> AckConsumer<C, T> {
+
+    /// failed events matched to messages by hash of key,
+    /// key must be unique in a batch
+    pub fn keyed<K, F>(inner: C, key: F) -> Self
+    where
+        F: Fn(&T) -> K + Send + Sync + 'static,
+        K: Hash
+    {
+        AckConsumer {
+            inner,
+            key: Box::new(move |event| hash(&key(event))),
+            failed: Vec::new()
+        }
+    }
+}
+
+
+#[async_trait]
+impl<T, C> Consumer<Message<T>> for AckConsumer<C, T>
+where
+    T: Send + 'static,
+    C: Consumer<T> + Send
+{
+    type Error = C::Error;
+
+    async fn init(&mut self) -> Result<(), C::Error> {
+        self.inner.init().await
+    }
+
+    async fn handle_events(&mut self, upstream_events: Vec<Message<T>>) -> Result<State<Message<T>>, C::Error> {
+        let (events, acks): (Vec<T>, Vec<Ack>) = upstream_events
+            .into_iter()
+            .map(Message::into_parts)
+            .unzip();
+
+        let keys: Vec<u64> = events.iter().map(|event| (self.key)(event)).collect();
+
+        let res = self.inner.handle_events(events).await;
+
+        // nack failed messages, keep rest to settle by result
+        let mut acks: Vec<Option<Ack>> = acks.into_iter().map(Some).collect();
+
+        for failed in self.inner.take_failed() {
+            let key = (self.key)(&failed.event);
+
+            let ack = keys.iter()
+                .zip(acks.iter_mut())
+                .find(|(k, ack)| **k == key && ack.is_some())
+                .and_then(|(_, ack)| ack.take());
+
+            self.failed.push(settle(failed, ack));
+        }
+
+        let acks = acks.into_iter().flatten();
+
+        match res {
+            Ok(State::Continue) => {
+                acks.for_each(Ack::ack);
+                Ok(State::Continue)
+            }
+            Ok(State::Terminate) => {
+                acks.for_each(Ack::ack);
+                Ok(State::Terminate)
+            }
+            Ok(State::DestinationDown(_)) => {
+                acks.for_each(|ack| ack.nack("destination down"));
+                Ok(State::DestinationDown(Vec::new()))
+            }
+            Err(err) => {
+                acks.for_each(|ack| ack.nack("handle failed"));
+                Err(err)
+            }
+        }
+    }
+
+    async fn terminate(&mut self) -> Result<(), C::Error> {
+        self.inner.terminate().await
+    }
+
+    async fn handle_tick(&mut self) -> Result<State<Message<T>>, C::Error> {
+        self.inner.handle_tick().await.map(without_events)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.inner.deadline()
+    }
+
+    async fn handle_deadline(&mut self) -> Result<State<Message<T>>, C::Error> {
+        self.inner.handle_deadline().await.map(without_events)
+    }
+
+    /// failed messages of last batch, and events failed
+    /// by timer callbacks as messages without acknowledger
+    fn take_failed(&mut self) -> Vec<Failed<Message<T>>> {
+        let mut failed = std::mem::take(&mut self.failed);
+
+        failed.extend(self.inner.take_failed().into_iter().map(|failed| settle(failed, None)));
+        failed
+    }
+}
+
+
+/// nack message of failed event with its reason, return failed message,
+/// without ack (event not from batch) message is unacked
+fn settle<T>(failed: Failed<T>, ack: Option<Ack>) -> Failed<Message<T>> {
+    let metadata = match ack {
+        Some(ack) => {
+            let metadata = ack.metadata.clone();
+            ack.nack(&failed.reason);
+            metadata
+        }
+        None => Metadata::default()
+    };
+
+    Failed {
+        event: Message::unacked(failed.event, metadata),
+        reason: failed.reason,
+        retryable: failed.retryable
+    }
+}
+
+
+fn hash<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+
+/// State of timer callbacks, they not have messages to keep
+fn without_events<T, U>(state: State<T>) -> State<U> {
+    match state {
+        State::Continue => State::Continue,
+        State::Terminate => State::Terminate,
+        State::DestinationDown(_) => State::DestinationDown(Vec::new())
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+
+
+/// Measurement recorded by a running stage
+#[derive(Debug, Clone, Copy)]
+pub enum Metric {
+
+    /// events received from upstream
+    EventsIn(usize),
+
+    /// events sent to downstream
+    EventsOut(usize),
+
+    /// events stage failed to process, sent to dead letter
+    Failed(usize),
+
+    /// one batch received from upstream, contain its size
+    Batch(usize),
+
+    /// time a callback took
+    Handler(Duration),
+
+    /// batches waiting in stage channel, and channel buffer in batches
+    Queue {
+        len      : usize,
+        capacity : usize
+    },
+
+    /// time dispatcher waited for downstream channels
+    DispatchWait(Duration),
+
+    /// time producer waited for subscribers demand
+    DemandWait(Duration)
+}
+
+
+
+/// Sink of stage metrics, set by runnables `with_metrics`
+///
+/// record called from stage task, must be cheap and not block
+pub trait Metrics: Send + Sync {
+    fn record(&self, stage: &str, metric: Metric);
+}
+
+
+
+#[inline]
+pub(crate) fn record(metrics: &Option<Arc<dyn Metrics>>, stage: &str, metric: Metric) {
+    if let Some(metrics) = metrics {
+        metrics.record(stage, metric)
+    }
+}
+
+
+
+
+/// upper bounds of batch size buckets
+const SIZE_BUCKETS: [f64; 6] = [1.0, 10.0, 100.0, 500.0, 1000.0, 10000.0];
+
+/// upper bounds of duration buckets, in seconds
+const SECONDS_BUCKETS: [f64; 7] = [0.0001, 0.001, 0.01, 0.1, 0.5, 1.0, 10.0];
+
+
+
+/// Histogram with fixed buckets, counts not cumulative
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    pub bounds : &'static [f64],
+    pub counts : Vec<u64>,
+    pub sum    : f64,
+    pub count  : u64
+}
+
+
+impl Histogram {
+
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// mean of observed values, zero if empty
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0
+        }
+
+        self.sum / self.count as f64
+    }
+}
+
+
+
+/// Metrics of one stage, copies of a stage with same name merged
+#[derive(Debug, Clone)]
+pub struct StageMetrics {
+    pub events_in      : u64,
+    pub events_out     : u64,
+    pub events_failed  : u64,
+    pub batches        : u64,
+    pub batch_size     : Histogram,
+    pub handler        : Histogram,
+    pub dispatch_wait  : Histogram,
+    pub demand_wait    : Histogram,
+
+    /// last reported, in batches
+    pub queue_len      : usize,
+    pub queue_capacity : usize
+}
+
+
+impl Default for StageMetrics {
+    fn default() -> Self {
+        StageMetrics {
+            events_in: 0,
+            events_out: 0,
+            events_failed: 0,
+            batches: 0,
+            batch_size: Histogram::new(&SIZE_BUCKETS),
+            handler: Histogram::new(&SECONDS_BUCKETS),
+            dispatch_wait: Histogram::new(&SECONDS_BUCKETS),
+            demand_wait: Histogram::new(&SECONDS_BUCKETS),
+            queue_len: 0,
+            queue_capacity: 0
+        }
+    }
+}
+
+
+impl StageMetrics {
+
+    fn apply(&mut self, metric: Metric) {
+        match metric {
+            Metric::EventsIn(n) => self.events_in += n as u64,
+            Metric::EventsOut(n) => self.events_out += n as u64,
+            Metric::Failed(n) => self.events_failed += n as u64,
+            Metric::Batch(size) => {
+                self.batches += 1;
+                self.batch_size.observe(size as f64);
+            }
+            Metric::Handler(took) => self.handler.observe(took.as_secs_f64()),
+            Metric::Queue { len, capacity } => {
+                self.queue_len = len;
+                self.queue_capacity = capacity;
+            }
+            Metric::DispatchWait(took) => self.dispatch_wait.observe(took.as_secs_f64()),
+            Metric::DemandWait(took) => self.demand_wait.observe(took.as_secs_f64())
+        }
+    }
+}
+
+
+
+/// Metrics of all stages by name
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub stages: BTreeMap<String, StageMetrics>
+}
+
+
+impl Snapshot {
+
+    /// Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+
+        counter(&mut text, "laststage_events_in_total", "events received from upstream",
+                self.stages.iter().map(|(name, m)| (name, m.events_in)));
+
+        counter(&mut text, "laststage_events_out_total", "events sent to downstream",
+                self.stages.iter().map(|(name, m)| (name, m.events_out)));
+
+        counter(&mut text, "laststage_events_failed_total", "events stage failed to process",
+                self.stages.iter().map(|(name, m)| (name, m.events_failed)));
+
+        counter(&mut text, "laststage_batches_total", "batches received from upstream",
+                self.stages.iter().map(|(name, m)| (name, m.batches)));
+
+        gauge(&mut text, "laststage_queue_length", "batches waiting in stage channel",
+              self.stages.iter().map(|(name, m)| (name, m.queue_len)));
+
+        gauge(&mut text, "laststage_queue_capacity", "stage channel buffer in batches",
+              self.stages.iter().map(|(name, m)| (name, m.queue_capacity)));
+
+        histogram(&mut text, "laststage_batch_size", "size of batches received from upstream",
+                  self.stages.iter().map(|(name, m)| (name, &m.batch_size)));
+
+        histogram(&mut text, "laststage_handler_seconds", "time callbacks took",
+                  self.stages.iter().map(|(name, m)| (name, &m.handler)));
+
+        histogram(&mut text, "laststage_dispatch_wait_seconds", "time dispatcher waited for downstream channels",
+                  self.stages.iter().map(|(name, m)| (name, &m.dispatch_wait)));
+
+        histogram(&mut text, "laststage_demand_wait_seconds", "time producer waited for subscribers demand",
+                  self.stages.iter().map(|(name, m)| (name, &m.demand_wait)));
+
+        text
+    }
+}
+
+
+
+/// In-memory Metrics, take snapshot to read or export
+///
+/// ```rust,ignore
+/// let metrics = Arc::new(MemoryMetrics::new());
+///
+/// let (log_chan, _) = ConsumerRunnable::new(Box::new(Log))
+///                     .with_metrics(metrics.clone())
+///                     .run(100);
+///
+/// println!("{}", metrics.snapshot().to_prometheus());
+/// ```
+#[derive(Default)]
+pub struct MemoryMetrics {
+    stages: Mutex<BTreeMap<String, StageMetrics>>
+}
+
+
+impl MemoryMetrics {
+
+    pub fn new() -> Self {
+        MemoryMetrics::default()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            stages: self.stages.lock().unwrap().clone()
+        }
+    }
+}
+
+
+impl Metrics for MemoryMetrics {
+    fn record(&self, stage: &str, metric: Metric) {
+        let mut stages = self.stages.lock().unwrap();
+
+        match stages.get_mut(stage) {
+            Some(metrics) => metrics.apply(metric),
+            None => {
+                let mut metrics = StageMetrics::default();
+                metrics.apply(metric);
+                stages.insert(stage.to_string(), metrics);
+            }
+        }
+    }
+}
+
+
+
+
+fn header(text: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+
+fn counter<'a>(text: &mut String, name: &str, help: &str, values: impl Iterator<Item = (&'a String, u64)>) {
+    header(text, name, help, "counter");
+    for (stage, value) in values {
+        let _ = writeln!(text, "{}{{stage=\"{}\"}} {}", name, escape(stage), value);
+    }
+}
+
+
+fn gauge<'a>(text: &mut String, name: &str, help: &str, values: impl Iterator<Item = (&'a String, usize)>) {
+    header(text, name, help, "gauge");
+    for (stage, value) in values {
+        let _ = writeln!(text, "{}{{stage=\"{}\"}} {}", name, escape(stage), value);
+    }
+}
+
+
+fn histogram<'a>(text: &mut String, name: &str, help: &str, values: impl Iterator<Item = (&'a String, &'a Histogram)>) {
+    header(text, name, help, "histogram");
+    for (stage, histogram) in values {
+        let stage = escape(stage);
+
+        // prometheus buckets are cumulative
+        let mut cumulative = 0;
+        for (bound, count) in histogram.bounds.iter().zip(histogram.counts.iter()) {
+            cumulative += count;
+            let _ = writeln!(text, "{}_bucket{{stage=\"{}\",le=\"{}\"}} {}", name, stage, bound, cumulative);
+        }
+
+        let _ = writeln!(text, "{}_bucket{{stage=\"{}\",le=\"+Inf\"}} {}", name, stage, histogram.count);
+        let _ = writeln!(text, "{}_sum{{stage=\"{}\"}} {}", name, stage, histogram.sum);
+        let _ = writeln!(text, "{}_count{{stage=\"{}\"}} {}", name, stage, histogram.count);
+    }
+}
+
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\")
+         .replace('"', "\\\"")
+         .replace('\n', "\\n")
+}
@@ -0,0 +1,684 @@
+// built-in modes of DispatcherType, implementations of Dispatch
+
+use std::future::{poll_fn, Future};
+use std::task::Poll;
+
+use async_trait::async_trait;
+
+use crate::Status;
+
+use super::DestinationDown;
+use super::dispatch::{Dispatch, Subscribers};
+use super::partition::Ring;
+use super::shared::Sharing;
+use super::subscription::Subscription;
+
+
+
+/// send events to next subscriber, every subscriber
+/// get at maximum its pending demand
+pub(crate) struct RoundRobin<Out> {
+    subscribers : Subscribers<Out>,
+    c           : usize
+}
+
+
+impl<Out> RoundRobin<Out> {
+
+    pub(crate) fn new() -> Self {
+        RoundRobin {
+            subscribers: Subscribers::new(),
+            c: 0
+        }
+    }
+
+    fn next_index(&mut self) -> usize {
+        let mut index = self.c;
+
+        self.c += 1;
+
+        if index >= self.subscribers.len() {
+            self.c = 1;
+            index = 0;
+        }
+
+        index
+    }
+}
+
+
+#[async_trait]
+impl<Out: Send> Dispatch<Out> for RoundRobin<Out> {
+
+    fn subscribers(&mut self) -> &mut Subscribers<Out> {
+        &mut self.subscribers
+    }
+
+    fn name(&self) -> &'static str {
+        "RoundRobin"
+    }
+
+
+    /// send events to next destination
+    ///
+    /// every destination get at maximum its pending demand,
+    /// remain events go to next destinations
+    ///
+    /// roundrobin is safe if a destination terminate
+    /// auto detect it and remove from destinations
+    async fn dispatch(&mut self, mut events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
+        while !events.is_empty() {
+
+            // wait until a destination ask for events
+            if self.wait_demand().await.is_err() {
+                return Err(DestinationDown(events))
+            }
+
+            // get next index
+            let index = self.next_index();
+
+            // take demand of destination
+            let taken = self.subscribers[index].take_demand(events.len());
+            if taken == 0 {
+                continue;
+            }
+
+            let rest = events.split_off(taken);
+
+            // send events
+            match self.subscribers[index].send(events).await {
+
+                // sending was successful
+                Ok(_ok) => {
+                    events = rest;
+                }
+
+                // channel closed
+                Err(err) => {
+
+                    // take ownership of events
+                    events = err.0;
+                    events.extend(rest);
+
+                    // remove this sender from subscribers
+                    self.subscribers.remove_at(index);
+
+                    // if not exist destination return Err
+                    if self.subscribers.is_empty() {
+                        return Err(DestinationDown(events))
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+
+
+/// send events to subscriber with most free channel slots,
+/// slow subscriber not stall others
+pub(crate) struct LeastLoaded<Out> {
+    subscribers : Subscribers<Out>,
+
+    // subscriber checked first, ties taken in turn
+    c           : usize
+}
+
+
+impl<Out> LeastLoaded<Out> {
+
+    pub(crate) fn new() -> Self {
+        LeastLoaded {
+            subscribers: Subscribers::new(),
+            c: 0
+        }
+    }
+
+    /// index and free slots of least loaded subscriber asked demand
+    fn least_loaded(&mut self) -> Option<(usize, usize)> {
+        let len = self.subscribers.len();
+
+        // index and free slots of best subscriber
+        let mut best: Option<(usize, usize)> = None;
+
+        for offset in 0..len {
+            let index = (self.c + offset) % len;
+            let sub = &self.subscribers[index];
+
+            if sub.pending_demand() == 0 {
+                continue;
+            }
+
+            let capacity = sub.capacity();
+            if best.is_none_or(|(_, free)| capacity > free) {
+                best = Some((index, capacity));
+            }
+        }
+
+        let (index, free) = best?;
+        self.c = index + 1;
+
+        Some((index, free))
+    }
+
+    /// every subscriber channel asked demand is full, wait until
+    /// first of them get free slot or any subscriber ask demand,
+    /// e.g. an idle one with free slots
+    async fn wait_free(&self) {
+        let mut waiting: Vec<_> = self.subscribers
+            .iter()
+            .filter(|sub| sub.pending_demand() > 0)
+            .map(|sub| Box::pin(sub.reserve()))
+            .collect();
+
+        let mut asked = Box::pin(self.subscribers.wait());
+
+        poll_fn(|cx| {
+            if asked.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(())
+            }
+
+            for reserve in waiting.iter_mut() {
+                if reserve.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(())
+                }
+            }
+
+            Poll::Pending
+        }).await
+    }
+}
+
+
+#[async_trait]
+impl<Out: Send> Dispatch<Out> for LeastLoaded<Out> {
+
+    fn subscribers(&mut self) -> &mut Subscribers<Out> {
+        &mut self.subscribers
+    }
+
+    fn name(&self) -> &'static str {
+        "LeastLoaded"
+    }
+
+
+    /// send events to least loaded destination, one with
+    /// most free channel slots asked demand
+    ///
+    /// every destination get at maximum its pending demand,
+    /// remain events go to next least loaded destination,
+    /// if every destination channel is full wait for first
+    /// one get free slot or any destination ask demand
+    async fn dispatch(&mut self, mut events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
+        while !events.is_empty() {
+
+            // wait until a destination ask for events
+            if self.wait_demand().await.is_err() {
+                return Err(DestinationDown(events))
+            }
+
+            let index = match self.least_loaded() {
+                Some((index, free)) if free > 0 => index,
+
+                // every channel full, wait for first free slot or new
+                // demand then choose again, stopped subscriber wake it
+                // and removed by wait_demand
+                Some(_) => {
+                    self.wait_free().await;
+                    continue;
+                }
+                None => continue
+            };
+
+            // take demand of destination
+            let taken = self.subscribers[index].take_demand(events.len());
+            if taken == 0 {
+                continue;
+            }
+
+            let rest = events.split_off(taken);
+
+            // channel closed, take ownership of events
+            // and remove this sender from subscribers
+            if let Err(err) = self.subscribers[index].send(events).await {
+                events = err.0;
+                events.extend(rest);
+
+                self.subscribers.remove_at(index);
+
+                // if not exist destination return Err
+                if self.subscribers.is_empty() {
+                    return Err(DestinationDown(events))
+                }
+
+                continue;
+            }
+
+            events = rest;
+        }
+
+        Ok(())
+    }
+}
+
+
+
+
+/// send events to all subscribers, in strict mode
+/// any subscriber stopped stop stage
+pub(crate) struct Broadcast<Out> {
+    subscribers : Subscribers<Out>,
+    clone       : fn(&[Out]) -> Vec<Out>,
+    strict      : bool
+}
+
+
+impl<Out> Broadcast<Out> {
+
+    pub(crate) fn new(clone: fn(&[Out]) -> Vec<Out>, strict: bool) -> Self {
+        Broadcast {
+            subscribers: Subscribers::new(),
+            clone,
+            strict
+        }
+    }
+}
+
+
+#[async_trait]
+impl<Out: Send> Dispatch<Out> for Broadcast<Out> {
+
+    fn subscribers(&mut self) -> &mut Subscribers<Out> {
+        &mut self.subscribers
+    }
+
+    fn name(&self) -> &'static str {
+        if self.strict { "BroadcastStrict" } else { "Broadcast" }
+    }
+
+
+    /// minimum of subscribers demand,
+    /// in strict mode Err if any subscriber stopped
+    async fn wait_demand(&mut self) -> Result<usize, DestinationDown<Out>> {
+        loop {
+
+            if self.strict && self.subscribers.iter().any(|sub| sub.is_closed()) {
+                return Err(DestinationDown(Vec::new()))
+            }
+
+            // remove terminated destinations
+            self.subscribers.remove_closed();
+
+            if self.subscribers.is_empty() {
+                return Err(DestinationDown(Vec::new()))
+            }
+
+            let demand = self.subscribers.demand_min();
+            if demand > 0 {
+                return Ok(demand)
+            }
+
+            self.subscribers.wait().await;
+        }
+    }
+
+
+    /// send events to all destinations
+    ///
+    /// events split by minimum demand of destinations,
+    /// last destination get batch itself without clone,
+    /// destinations with selector get only selected events,
+    /// filtered before clone
+    ///
+    /// terminated destinations removed, if not exist
+    /// any destination return Err, in BroadcastStrict
+    /// return Err when first destination terminated
+    async fn dispatch(&mut self, mut events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
+        while !events.is_empty() {
+
+            let demand = match self.wait_demand().await {
+                Ok(demand) => demand,
+                Err(_) => return Err(DestinationDown(events))
+            };
+
+            let mut rest = events.split_off(demand.min(events.len()));
+
+            let mut index = 0;
+            while index < self.subscribers.len() {
+                let sub = &self.subscribers[index];
+
+                let last = index + 1 == self.subscribers.len();
+                let batch = match (last, sub.is_selective()) {
+                    (true, false) => std::mem::take(&mut events),
+                    (false, false) => (self.clone)(&events),
+
+                    // keep not selected events, returned if send failed
+                    (true, true) => {
+                        let (batch, kept) = std::mem::take(&mut events)
+                            .into_iter()
+                            .partition(|event| sub.selects(event));
+
+                        events = kept;
+                        batch
+                    }
+                    (false, true) => selected(&events, sub, self.clone)
+                };
+
+                if batch.is_empty() {
+                    index += 1;
+                    continue;
+                }
+
+                // take only demand of events sent, destination
+                // ask again by events it received
+                sub.take_demand(batch.len());
+
+                // channel closed
+                if let Err(mut err) = sub.send(batch).await {
+                    if last {
+                        events.append(&mut err.0);
+                    }
+
+                    if self.strict {
+                        events.append(&mut rest);
+                        return Err(DestinationDown(events))
+                    }
+
+                    // remove this sender from subscribers
+                    self.subscribers.remove_at(index);
+                    continue;
+                }
+
+                index += 1;
+            }
+
+            // if not exist destination return Err
+            if self.subscribers.is_empty() {
+                events.append(&mut rest);
+                return Err(DestinationDown(events))
+            }
+
+            events = rest;
+        }
+
+        Ok(())
+    }
+}
+
+
+
+
+/// clone events selected by subscriber,
+/// contiguous selected events cloned at once
+fn selected<Out>(events: &[Out], sub: &Subscription<Out>, clone: fn(&[Out]) -> Vec<Out>) -> Vec<Out> {
+    let mut batch = Vec::new();
+
+    // start of current run of selected events
+    let mut start = None;
+
+    for (index, event) in events.iter().enumerate() {
+        match (sub.selects(event), start) {
+            (true, None) => start = Some(index),
+            (false, Some(from)) => {
+                extend(&mut batch, clone(&events[from..index]));
+                start = None;
+            }
+            _ => ()
+        }
+    }
+
+    if let Some(from) = start {
+        extend(&mut batch, clone(&events[from..]));
+    }
+
+    batch
+}
+
+
+#[inline]
+fn extend<Out>(batch: &mut Vec<Out>, mut run: Vec<Out>) {
+    if batch.is_empty() {
+        *batch = run;
+    } else {
+        batch.append(&mut run);
+    }
+}
+
+
+
+
+/// send same shared batches to all subscribers, fan-out
+/// only copy pointer of batches, stopped subscribers removed
+pub(crate) struct BroadcastShared<Out> {
+    subscribers : Subscribers<Out>,
+    sharing     : Sharing<Out>
+}
+
+
+impl<Out> BroadcastShared<Out> {
+
+    pub(crate) fn new(sharing: Sharing<Out>) -> Self {
+        BroadcastShared {
+            subscribers: Subscribers::new(),
+            sharing
+        }
+    }
+}
+
+
+#[async_trait]
+impl<Out: Send> Dispatch<Out> for BroadcastShared<Out> {
+
+    fn subscribers(&mut self) -> &mut Subscribers<Out> {
+        &mut self.subscribers
+    }
+
+    fn name(&self) -> &'static str {
+        "BroadcastShared"
+    }
+
+    /// subscriber count its demand by events in shared batches
+    fn subscribe(&mut self, subscription: Subscription<Out>) -> Result<(), Status> {
+        subscription.count_by(self.sharing.count);
+        self.subscribers.add(subscription)
+    }
+
+
+    /// minimum of subscribers demand, in events
+    async fn wait_demand(&mut self) -> Result<usize, DestinationDown<Out>> {
+        loop {
+
+            // remove terminated destinations
+            self.subscribers.remove_closed();
+
+            if self.subscribers.is_empty() {
+                return Err(DestinationDown(Vec::new()))
+            }
+
+            let demand = self.subscribers.demand_min();
+            if demand > 0 {
+                return Ok(demand)
+            }
+
+            self.subscribers.wait().await;
+        }
+    }
+
+
+    /// send same shared batches to all destinations
+    ///
+    /// batches split by minimum demand of destinations, in events,
+    /// both halves share same batch, every destination get pointer
+    /// copies of batches, last destination get them without copy
+    ///
+    /// terminated destinations removed, if not exist
+    /// any destination return Err
+    async fn dispatch(&mut self, mut events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
+        let Sharing { clone, count, take } = self.sharing;
+
+        while count(&events) > 0 {
+
+            let demand = match self.wait_demand().await {
+                Ok(demand) => demand,
+                Err(_) => return Err(DestinationDown(events))
+            };
+
+            let mut batch = take(&mut events, demand);
+            let len = count(&batch);
+
+            let mut index = 0;
+            while index < self.subscribers.len() {
+                self.subscribers[index].take_demand(len);
+
+                let last = index + 1 == self.subscribers.len();
+                let shared = if last { std::mem::take(&mut batch) } else { clone(&batch) };
+
+                // channel closed, remove this sender from subscribers
+                if let Err(mut err) = self.subscribers[index].send(shared).await {
+                    if last {
+                        batch.append(&mut err.0);
+                    }
+
+                    self.subscribers.remove_at(index);
+                    continue;
+                }
+
+                index += 1;
+            }
+
+            // if not exist destination return Err
+            if self.subscribers.is_empty() {
+                batch.append(&mut events);
+                return Err(DestinationDown(batch))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+
+
+/// send every event to subscriber owns its key
+/// on consistent hash ring
+pub(crate) struct Partition<Out> {
+    subscribers : Subscribers<Out>,
+    key         : Box<dyn Fn(&Out) -> u64 + Send + Sync>,
+    ring        : Ring
+}
+
+
+impl<Out> Partition<Out> {
+
+    pub(crate) fn new(key: Box<dyn Fn(&Out) -> u64 + Send + Sync>) -> Self {
+        Partition {
+            subscribers: Subscribers::new(),
+            key,
+            ring: Ring::new()
+        }
+    }
+
+    /// id of running subscriber owns key, stopped subscribers
+    /// removed from ring on the way, from subscribers by send
+    fn owner(&mut self, key: u64) -> Option<u64> {
+        loop {
+            let id = self.ring.get(key)?;
+
+            match self.subscribers.position(id) {
+                Some(index) if !self.subscribers[index].is_closed() => return Some(id),
+                _ => self.ring.remove(id)
+            }
+        }
+    }
+}
+
+
+#[async_trait]
+impl<Out: Send> Dispatch<Out> for Partition<Out> {
+
+    fn subscribers(&mut self) -> &mut Subscribers<Out> {
+        &mut self.subscribers
+    }
+
+    fn name(&self) -> &'static str {
+        "Partition"
+    }
+
+    fn subscribe(&mut self, subscription: Subscription<Out>) -> Result<(), Status> {
+        let id = subscription.id();
+
+        self.subscribers.add(subscription)?;
+        self.ring.add(id);
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, subscription: &Subscription<Out>) -> Result<(), Status> {
+        let removed = self.subscribers.remove(subscription)?;
+        self.ring.remove(removed.id());
+        Ok(())
+    }
+
+
+    /// send every event to destination owns its key
+    ///
+    /// events split by destination, order of events with
+    /// same key is kept, if a destination terminate its
+    /// keys move to other destinations by hash ring
+    async fn dispatch(&mut self, mut events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
+        while !events.is_empty() {
+
+            // split events by id of destination, index
+            //  change when a destination removed
+            let mut partitions: Vec<(u64, Vec<Out>)> = Vec::new();
+
+            let mut batch = std::mem::take(&mut events).into_iter();
+
+            while let Some(event) = batch.next() {
+                let key = (self.key)(&event);
+
+                let id = match self.owner(key) {
+                    Some(id) => id,
+                    None => {
+                        // not exist any destination
+                        let mut unsent: Vec<Out> = partitions
+                            .into_iter()
+                            .flat_map(|(_, partition)| partition)
+                            .collect();
+
+                        unsent.push(event);
+                        unsent.extend(batch);
+                        return Err(DestinationDown(unsent))
+                    }
+                };
+
+                match partitions.iter_mut().find(|(pid, _)| *pid == id) {
+                    Some((_, partition)) => partition.push(event),
+                    None => partitions.push((id, vec![event]))
+                }
+            }
+
+            // events of terminated destinations,
+            //  dispatch again to new owners
+            for (id, partition) in partitions {
+                let index = match self.subscribers.position(id) {
+                    Some(index) => index,
+                    None => {
+                        events.extend(partition);
+                        continue;
+                    }
+                };
+
+                if let Err(DestinationDown(rest)) = self.subscribers.send(index, partition).await {
+                    self.ring.remove(id);
+                    events.extend(rest);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
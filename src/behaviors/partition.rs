@@ -0,0 +1,62 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use hashring::HashRing;
+
+
+
+/// virtual nodes per subscriber on ring,
+/// more vnodes spread keys more even between subscribers
+const VNODES: usize = 64;
+
+
+
+/// wrap key extractor to return hash of event key
+pub(crate) fn partition_key<Out, K, F>(key: F) -> Box<dyn Fn(&Out) -> u64 + Send + Sync>
+where
+    F: Fn(&Out) -> K + Send + Sync + 'static,
+    K: Hash
+{
+    Box::new(move |event| {
+        let mut hasher = DefaultHasher::new();
+        key(event).hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+
+
+
+/// Consistent hash ring over subscribers
+///
+/// when a subscriber removed only keys of that
+/// subscriber move to others, other keys stay on same subscriber
+pub(crate) struct Ring {
+    ring: HashRing<(u64, usize)>
+}
+
+
+impl Ring {
+
+    pub(crate) fn new() -> Self {
+        Ring {
+            ring: HashRing::new()
+        }
+    }
+
+    pub(crate) fn add(&mut self, id: u64) {
+        self.ring.batch_add((0..VNODES).map(|vnode| (id, vnode)).collect());
+    }
+
+    pub(crate) fn remove(&mut self, id: u64) {
+        for vnode in 0..VNODES {
+            self.ring.remove(&(id, vnode));
+        }
+    }
+
+    /// return id of subscriber owns this key
+    #[inline]
+    pub(crate) fn get(&self, key: u64) -> Option<u64> {
+        self.ring.get(&key).map(|(id, _)| *id)
+    }
+}
@@ -0,0 +1,323 @@
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::Status;
+
+use super::DispatcherType;
+use super::consumer::{Consumer, ConsumerRunnable};
+use super::metrics::Metrics;
+use super::producer::{Producer, ProducerRunnable};
+use super::producer_consumer::{ProducerConsumer, ProducerConsumerRunnable};
+use super::shutdown::Shutdown;
+use super::stage::Running;
+use super::subscription::{self, Subscription};
+use super::topology::{StageKind, Topology};
+
+
+
+/// default channel buffer of pipeline stages
+const DEFAULT_BUFFER: usize = 100;
+
+
+/// build tail stage, subscribe it to downstream subscriptions
+type Build<T> = Box<dyn FnOnce(Vec<Subscription<T>>, Option<DispatcherFactory<T>>, &mut Spawned) -> Result<(), Status> + Send>;
+
+type DispatcherFactory<T> = Box<dyn FnMut() -> DispatcherType<T> + Send>;
+
+
+
+/// Build pipeline front-to-back, T is events of last stage
+///
+/// stages spawned back-to-front when `to` called,
+/// for custom demand / on_error / tick use runnables directly
+///
+/// `name` / `dispatcher` apply to last added stage,
+/// `buffer` apply to stages added after it
+///
+/// ```rust,ignore
+/// // Producer -> 4 * FilterByAge -> 2 * Log
+/// let pipeline = Pipeline::from(Prod)
+///                     .via(|| FilterByAge, 4).name("filter")
+///                     .dispatcher(|| DispatcherType::partition(|pe: &ProdEvent| pe.age))
+///                     .to(|| Log, 2)
+///                     .unwrap();
+///
+/// pipeline.shutdown(Duration::from_secs(5)).await.unwrap();
+/// ```
+pub struct Pipeline<T> {
+    build      : Build<T>,
+    dispatcher : Option<DispatcherFactory<T>>,
+    buffer     : usize,
+    metrics    : Option<Arc<dyn Metrics>>,
+
+    topology   : Topology,
+
+    // index of last stage in topology
+    // and name of its dispatcher
+    tail       : usize,
+    tail_dt    : &'static str
+}
+
+
+/// stages spawned by pipeline
+struct Spawned {
+    shutdown : Shutdown,
+    stages   : Vec<Box<dyn Running>>,
+    topology : Topology,
+    metrics  : Option<Arc<dyn Metrics>>
+}
+
+
+impl Spawned {
+
+    fn name(&self, stage: usize) -> String {
+        self.topology.stages[stage].name.clone()
+    }
+}
+
+
+
+impl<T: Send + 'static> Pipeline<T> {
+
+    /// start pipeline by producer
+    #[allow(clippy::should_implement_trait)]
+    pub fn from<P, E>(producer: P) -> Self
+    where
+        P: Producer<T, Error = E> + Send + 'static,
+        E: Send + 'static
+    {
+        let mut topology = Topology::new();
+        let tail = topology.stage("producer", StageKind::Producer, 1, None);
+
+        let build: Build<T> = Box::new(move |subscribe_to, dispatcher, spawned| {
+            let dispatcher_type = dispatcher.map(|mut dispatcher| dispatcher());
+
+            let mut runnable = ProducerRunnable::new(Box::new(producer),
+                                                     subscribe_to,
+                                                     dispatcher_type,
+                                                     subscription::DEFAULT_MAX_DEMAND,
+                                                     spawned.shutdown.producer())?
+                                .with_shutdown(&spawned.shutdown)
+                                .with_name(spawned.name(tail));
+
+            if let Some(metrics) = &spawned.metrics {
+                runnable = runnable.with_metrics(metrics.clone());
+            }
+
+            let (stage, _) = runnable.run();
+
+            spawned.stages.push(Box::new(stage));
+            Ok(())
+        });
+
+        Pipeline {
+            build,
+            dispatcher: None,
+            buffer: DEFAULT_BUFFER,
+            metrics: None,
+            topology,
+            tail,
+            tail_dt: "RoundRobin"
+        }
+    }
+
+
+    /// dispatcher of last stage, called for every copy of it,
+    /// default RoundRobin
+    pub fn dispatcher<F>(mut self, mut dispatcher: F) -> Self
+    where
+        F: FnMut() -> DispatcherType<T> + Send + 'static
+    {
+        self.tail_dt = dispatcher().name();
+        self.dispatcher = Some(Box::new(dispatcher));
+        self
+    }
+
+
+    /// channel buffer of next stages, default 100
+    pub fn buffer(mut self, buffer: usize) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+
+    /// record metrics of all stages to sink
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+
+    /// name of last stage, default is its kind
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.topology.stages[self.tail].name = name.into();
+        self
+    }
+
+
+    /// stages and edges added so far
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+
+    /// add ProducerConsumer stage, run `concurrency` copies of it
+    /// every copy subscribe to all copies of next stage
+    pub fn via<Out, E, S, F>(self, mut stage: F, concurrency: usize) -> Pipeline<Out>
+    where
+        Out: Send + 'static,
+        E: Send + 'static,
+        S: ProducerConsumer<T, Out, Error = E> + Send + 'static,
+        F: FnMut() -> S + Send + 'static
+    {
+        let Pipeline { build: upstream, dispatcher: upstream_dispatcher, buffer, metrics, mut topology, tail, tail_dt } = self;
+
+        let index = topology.stage("producer_consumer", StageKind::ProducerConsumer, concurrency, Some(buffer));
+        topology.edge(tail, index, tail_dt);
+
+        let build: Build<Out> = Box::new(move |subscribe_to, mut dispatcher, spawned| {
+            let mut subscriptions = Vec::with_capacity(concurrency);
+
+            for _ in 0..concurrency {
+                let dispatcher_type = dispatcher.as_mut().map(|dispatcher| dispatcher());
+
+                let mut runnable = ProducerConsumerRunnable::new(Box::new(stage()),
+                                                                 subscribe_to.clone(),
+                                                                 dispatcher_type)?
+                                    .with_shutdown(&spawned.shutdown)
+                                    .with_name(spawned.name(index));
+
+                if let Some(metrics) = &spawned.metrics {
+                    runnable = runnable.with_metrics(metrics.clone());
+                }
+
+                let (subscription, stage, _) = runnable.run(buffer);
+
+                spawned.stages.push(Box::new(stage));
+                subscriptions.push(subscription);
+            }
+
+            upstream(subscriptions, upstream_dispatcher, spawned)
+        });
+
+        Pipeline {
+            build,
+            dispatcher: None,
+            buffer,
+            metrics,
+            topology,
+            tail: index,
+            tail_dt: "RoundRobin"
+        }
+    }
+
+
+    /// end pipeline by Consumer stage, run `concurrency` copies of it,
+    /// then spawn whole pipeline
+    pub fn to<E, S, F>(self, stage: F, concurrency: usize) -> Result<PipelineHandle, Status>
+    where
+        E: Send + 'static,
+        S: Consumer<T, Error = E> + Send + 'static,
+        F: FnMut() -> S
+    {
+        self.to_named("consumer", stage, concurrency)
+    }
+
+
+    /// like `to`, with name of Consumer stage
+    pub fn to_named<E, S, F>(self, name: impl Into<String>, mut stage: F, concurrency: usize) -> Result<PipelineHandle, Status>
+    where
+        E: Send + 'static,
+        S: Consumer<T, Error = E> + Send + 'static,
+        F: FnMut() -> S
+    {
+        let mut topology = self.topology;
+        let index = topology.stage(name, StageKind::Consumer, concurrency, Some(self.buffer));
+        topology.edge(self.tail, index, self.tail_dt);
+
+        let mut spawned = Spawned {
+            shutdown: Shutdown::new(),
+            stages: Vec::new(),
+            topology,
+            metrics: self.metrics
+        };
+
+        let mut subscriptions = Vec::with_capacity(concurrency);
+
+        for _ in 0..concurrency {
+            let mut runnable = ConsumerRunnable::new(Box::new(stage()))
+                                .with_shutdown(&spawned.shutdown)
+                                .with_name(spawned.name(index));
+
+            if let Some(metrics) = &spawned.metrics {
+                runnable = runnable.with_metrics(metrics.clone());
+            }
+
+            let (subscription, stage) = runnable.run(self.buffer);
+
+            spawned.stages.push(Box::new(stage));
+            subscriptions.push(subscription);
+        }
+
+        (self.build)(subscriptions, self.dispatcher, &mut spawned)?;
+
+        Ok(PipelineHandle {
+            shutdown: spawned.shutdown,
+            stages: spawned.stages,
+            topology: spawned.topology
+        })
+    }
+}
+
+
+
+
+/// Handle of running pipeline
+pub struct PipelineHandle {
+    shutdown : Shutdown,
+    stages   : Vec<Box<dyn Running>>,
+    topology : Topology
+}
+
+
+impl PipelineHandle {
+
+    /// stages and edges of running pipeline
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    /// stop producers and wait until every stage
+    /// drained its channel and terminated
+    ///
+    /// return Err(DeadlineElapsed) if pipeline not stopped before
+    /// deadline, then running stages aborted
+    pub async fn shutdown(self, deadline: Duration) -> Result<(), Status> {
+        self.shutdown.shutdown(deadline).await
+    }
+
+    /// wait until every stage stopped, e.g. producer emitted Emit::Done
+    ///
+    /// return Err(StageFailed) if any stage failed
+    pub async fn join(self) -> Result<(), Status> {
+        let mut failed = false;
+
+        for mut stage in self.stages {
+            failed |= poll_fn(|cx| stage.poll_exit(cx)).await;
+        }
+
+        if failed {
+            return Err(Status::StageFailed)
+        }
+
+        Ok(())
+    }
+
+    /// stop every stage immediately, events in channels are lost
+    pub fn abort(&self) {
+        for stage in self.stages.iter() {
+            stage.abort()
+        }
+    }
+}
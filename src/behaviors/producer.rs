@@ -1,114 +1,303 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::task::JoinHandle;
-use tokio::sync::mpsc::Sender;
 use async_trait::async_trait;
 use tokio::sync::oneshot;
 
 use crate::Status;
 
-use super::{Dispatcher, DestinationDown, DispatcherType};
+use super::{Dispatcher, DispatcherHandle, DispatcherType};
+use super::subscription::Subscription;
+use super::shutdown::{Shutdown, ShutdownToken};
+use super::stage::{ExitReason, StageHandle};
+use super::error::{OnError, StageError};
+use super::idle::{Idle, IdleState, IdleWaker};
+use super::metrics::{self, Metric, Metrics};
+use super::trace::{self, event, Traced};
+
+
+
+/// Returned by handle_demand
+pub enum Emit<Out> {
+
+    /// dispatch events and wait for more demand
+    Events(Vec<Out>),
+
+    /// dispatch last events, then terminate producer,
+    /// downstream stages terminate when drained their channel
+    Done(Vec<Out>)
+}
 
 
 
 #[async_trait]
 pub trait Producer<Out> {
-    
-    /// init used for initialize producer
-    async fn init(&mut self);
 
-    /// produce events, at maximum (max_demand)
-    async fn handle_demand(&mut self, max_demand: usize) -> Vec<Out>;
+    /// error returned by callbacks
+    type Error: Send;
+
+    /// init used for initialize producer,
+    /// if failed producer never start
+    async fn init(&mut self) -> Result<(), Self::Error>;
+
+    /// produce events, at maximum (demand)
+    ///
+    /// demand is events subscribers asked and not received yet,
+    /// return Emit::Done when producer has nothing more to produce
+    async fn handle_demand(&mut self, demand: usize) -> Result<Emit<Out>, Self::Error>;
 
-    async fn terminate(&mut self);
+    async fn terminate(&mut self) -> Result<(), Self::Error>;
 }
 
 
 // -----------------------------------------
 
-pub struct ProducerRunnable<Out> {
-    proc         : Box<dyn Producer<Out> + Send>,
+pub struct ProducerRunnable<Out, E> {
+    name         : String,
+    proc         : Box<dyn Producer<Out, Error = E> + Send>,
     dispatcher   : Dispatcher<Out>,
+    on_error     : OnError<E>,
+    idle         : IdleState,
+    metrics      : Option<Arc<dyn Metrics>>,
 
     max_demand   : usize,
-    shutdown     : oneshot::Receiver<()>
+    shutdown     : oneshot::Receiver<()>,
+
+    // dropped when producer stopped
+    token        : Option<ShutdownToken>
 
 }
 
 
 
-impl<Out> ProducerRunnable<Out> 
+impl<Out, E> ProducerRunnable<Out, E>
 where
-    Out: Clone + Send + 'static 
+    Out: Send + 'static,
+    E: Send + 'static
 {
-    pub fn new(proc: Box<dyn Producer<Out> + Send>,
-               subscribe_to: Vec<Sender<Vec<Out>>>,
-               dispatcher_type: Option<DispatcherType>,
+    /// max_demand is maximum events asked from producer by one handle_demand call
+    pub fn new(proc: Box<dyn Producer<Out, Error = E> + Send>,
+               subscribe_to: Vec<Subscription<Out>>,
+               dispatcher_type: Option<DispatcherType<Out>>,
                max_demand: usize,
-               shutdown: oneshot::Receiver<()>)            
-               
+               shutdown: oneshot::Receiver<()>)
+
     ->  Result<Self, Status>
-    
+
     {
 
         // Check subscribe_to not be empty
-        if subscribe_to.len() == 0 {
+        if subscribe_to.is_empty() {
             return Err(Status::SenderNotFound);
         }
 
+        // Check max_demand
+        if max_demand == 0 {
+            return Err(Status::InvalidDemand);
+        }
+
         // if dispatcher_type is None, set RoundRobin
-        let dt = if let Some(dt) = dispatcher_type { dt } 
+        let dt = if let Some(dt) = dispatcher_type { dt }
                                 else { DispatcherType::RoundRobin };
-        
-        
+
+
         // Check subscribe_to not have duplicate sender
-        match Dispatcher::new(subscribe_to, dt) {
-            Ok(dispatcher) => {
-                Ok(Self {
-                    proc,
-                    dispatcher,
-                    max_demand,
-                    shutdown,
-                })        
-            }
-            Err(a) => {
-                Err(a)
+        let dispatcher = Dispatcher::new(subscribe_to, dt)?;
+
+        Ok(Self {
+            name: String::from("producer"),
+            proc,
+            dispatcher,
+            on_error: OnError::Stop,
+            idle: IdleState::new(Idle::default(), None),
+            metrics: None,
+            max_demand,
+            shutdown,
+            token: None
+        })
+    }
+
+
+    /// name of stage, default is its kind
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+
+    /// track producer by pipeline shutdown, pass `Shutdown::producer`
+    /// receiver to new, then `Shutdown::shutdown` stop producer
+    /// and wait until it terminated
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.token = Some(shutdown.token());
+        self
+    }
+
+
+
+    /// set what to do when handle_demand failed, default OnError::Stop
+    pub fn with_on_error(mut self, on_error: OnError<E>) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+
+
+    /// record stage metrics to sink
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+
+    /// run by supervisor, when all subscribers stopped wait
+    /// grace period for restarted one instead of stop
+    pub(crate) fn supervised(mut self, grace: Duration) -> Self {
+        self.dispatcher.supervise(grace);
+        self
+    }
+
+
+    /// set what to do when handle_demand returned no events,
+    /// default Idle::Backoff from 1ms up to 100ms
+    ///
+    /// waker let producer wake runner before idle wait elapsed
+    pub fn with_idle(mut self, idle: Idle, waker: Option<IdleWaker>) -> Self {
+        self.idle = IdleState::new(idle, waker);
+        self
+    }
+
+
+
+    /// produce events for demand and send to dst/subscribe_to by dispatcher
+    ///
+    /// return Ok(true) if producer emitted Done,
+    /// return Err(reason) if producer must stop
+    #[inline]
+    pub async fn produce_to_dst(&mut self, demand: usize) -> Result<bool, ExitReason<Out, E>> {
+        let start = Instant::now();
+        let demand = demand.min(self.max_demand);
+        let res = self.proc.handle_demand(demand).in_call("handle_demand", demand).await;
+        metrics::record(&self.metrics, &self.name, Metric::Handler(start.elapsed()));
+
+        let (events, done) = match res {
+            Ok(Emit::Events(events)) => (events, false),
+            Ok(Emit::Done(events)) => (events, true),
+            Err(err) => {
+                self.idle.empty();
+                event!(warn, "callback failed");
+
+                return self.on_error
+                    .apply(StageError::Handle(err))
+                    .map(|_| false)
+                    .map_err(ExitReason::Failed)
             }
+        };
+
+        // never dispatch empty batch
+        if events.is_empty() {
+            self.idle.empty();
+            return Ok(done)
         }
-        
+
+        self.idle.reset();
+        metrics::record(&self.metrics, &self.name, Metric::EventsOut(events.len()));
+
+        let start = Instant::now();
+        let len = events.len();
+        let res = self.dispatcher.dispatch(events).in_call("dispatch", len).await;
+        metrics::record(&self.metrics, &self.name, Metric::DispatchWait(start.elapsed()));
+
+        res.map_err(|dd| ExitReason::DestinationDown(dd.0))?;
+
+        Ok(done)
     }
 
 
+    async fn terminate(&mut self) -> ExitReason<Out, E> {
+        if let Err(err) = self.proc.terminate().await {
+            return ExitReason::Failed(StageError::Terminate(err))
+        }
 
-    /// produce events and send to dst/subscribe_to by dispatcher
-    #[inline]
-    pub async fn produce_to_dst(&mut self) -> Result<(), DestinationDown<Out>> {
-        let events = self.proc.handle_demand(self.max_demand).await;
-        self.dispatcher.dispatch(events).await
+        event!(info, "terminate");
+        ExitReason::Normal
     }
 
 
+    /// run producer, return stage handle to await its ExitReason and
+    /// dispatcher handle to subscribe / unsubscribe at runtime
     #[inline]
-    pub fn run(mut self) -> JoinHandle<Option<DestinationDown<Out>>> {
-        
-        tokio::spawn(async move {
-            
-            self.proc.init().await;
+    pub fn run(mut self) -> (StageHandle<Out, E>, DispatcherHandle<Out>) {
+
+        let handle = self.dispatcher.handle();
+
+        let name = self.name.clone();
+        let token = self.token.clone();
+
+        let stage = async move {
+
+            if let Err(err) = self.proc.init().await {
+                return ExitReason::Failed(StageError::Init(err))
+            }
+
+            event!(info, "init");
+
+            // if shutdown sender dropped, never listen on it again
+            let mut shutdown_dropped = false;
 
             loop {
 
-                // If recv shutdown notify, call terminate   
-                if let Ok(_) = self.shutdown.try_recv() {
-                    self.proc.terminate().await;
-                    return None
-                }
-                
+                // wait for subscribers demand, or idle wait if last poll was empty
+                // If recv shutdown notify, call terminate
+                let waiting = Instant::now();
+
+                let demand = tokio::select! {
+                    res = &mut self.shutdown, if !shutdown_dropped => {
+                        if res.is_ok() {
+                            event!(info, "shutdown");
+                            return self.terminate().await
+                        }
+
+                        shutdown_dropped = true;
+                        continue;
+                    }
+                    _ = self.idle.wait(), if self.idle.pending() => continue,
+                    demand = self.dispatcher.wait_demand(), if !self.idle.pending() => {
+                        metrics::record(&self.metrics, &self.name, Metric::DemandWait(waiting.elapsed()));
+                        demand
+                    }
+                };
+
                 // produce events and dispatch
-                match self.produce_to_dst().await {
-                    Err(dd) => return Some(dd),
-                    Ok(_) => (),
-                }                
+                let res = match demand {
+                    Ok(demand) => self.produce_to_dst(demand).await,
+                    Err(dd) => Err(ExitReason::DestinationDown(dd.0))
+                };
+
+                match res {
+                    Ok(false) => (),
+
+                    // producer done, terminate and drop dispatcher
+                    // then downstream stages drain and terminate
+                    Ok(true) => {
+                        event!(info, "done");
+                        return self.terminate().await
+                    }
+                    Err(reason) => return reason
+                }
             }
-        })
+        };
+
+        let join = tokio::spawn(trace::exited(stage).in_stage(&name, "producer"));
+
+        if let Some(token) = token {
+            token.track(join.abort_handle());
+        }
+
+        (StageHandle::new(join), handle)
     }
 }
-
@@ -1,117 +1,449 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use tokio::sync::mpsc::channel;
 use crate::Status;
-use tokio::sync::mpsc::Sender;
 use async_trait::async_trait;
 
-use super::{Dispatcher, DestinationDown, DispatcherType};
+use super::{Dispatcher, DispatcherHandle, DispatcherType};
+use super::subscription::{self, Asker, Demand, Subscription};
+use super::shutdown::{Shutdown, ShutdownToken};
+use super::stage::{self, ExitReason, StageHandle};
+use super::error::{OnError, StageError};
+use super::dead_letter::{self, DeadLetterTo, Failed};
+use super::retry::{Retry, Retrying};
+use super::metrics::{self, Metric, Metrics};
+use super::trace::{self, event, Traced};
 
 
 
 #[async_trait]
 pub trait ProducerConsumer<In, Out> {
-    
-    /// init used for initialize producer
-    async fn init(&mut self);
 
-    /// receive events from upstream and return events as downstream to next destination 
-    async fn handle_events(&mut self, upstream_events: Vec<In>) -> Vec<Out>;
+    /// error returned by callbacks
+    type Error: Send;
+
+    /// init used for initialize producer,
+    /// if failed stage never start
+    async fn init(&mut self) -> Result<(), Self::Error>;
+
+    /// receive events from upstream and return events as downstream to next destination
+    async fn handle_events(&mut self, upstream_events: Vec<In>) -> Result<Vec<Out>, Self::Error>;
+
+    async fn terminate(&mut self) -> Result<(), Self::Error>;
+
+
+    /// called every tick interval set by `with_tick`,
+    /// return events to next destination
+    async fn handle_tick(&mut self) -> Result<Vec<Out>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// one-off timer, when runner call handle_deadline,
+    /// asked again after every callback, default never
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// called when deadline reached, return events to next destination
+    async fn handle_deadline(&mut self) -> Result<Vec<Out>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// called when upstream closed before terminate,
+    /// return events still held by stage to next destination
+    async fn drain(&mut self) -> Result<Vec<Out>, Self::Error> {
+        Ok(Vec::new())
+    }
 
-    async fn terminate(&mut self);
+    /// events failed by last callback with their reason,
+    /// runner take them after every callback and send to dead letter
+    fn take_failed(&mut self) -> Vec<Failed<In>> {
+        Vec::new()
+    }
 }
 
 
 // -----------------------------------------
 
-pub struct ProducerConsumerRunnable<In, Out> {
-    proc         : Box<dyn ProducerConsumer<In, Out> + Send>,
-    dispatcher   : Dispatcher<Out>
+pub struct ProducerConsumerRunnable<In, Out, E> {
+    name         : String,
+    proc         : Box<dyn ProducerConsumer<In, Out, Error = E> + Send>,
+    dispatcher   : Dispatcher<Out>,
+    on_error     : OnError<E>,
+    metrics      : Option<Arc<dyn Metrics>>,
+    dead_letter  : Option<DeadLetterTo<In>>,
+    retry        : Option<Retrying<In, E>>,
+
+    min_demand   : usize,
+    max_demand   : usize,
+    tick         : Option<Duration>,
+
+    // dropped when stage stopped
+    token        : Option<ShutdownToken>
 
 }
 
 
-impl<In, Out> ProducerConsumerRunnable<In, Out> 
+impl<In, Out, E> ProducerConsumerRunnable<In, Out, E>
 where
-    In:  Clone + Send + 'static,
-    Out: Clone + Send + 'static 
+    In:  Send + 'static,
+    Out: Send + 'static,
+    E:   Send + 'static
 {
-    pub fn new(proc            : Box<dyn ProducerConsumer<In, Out> + Send>,
-               subscribe_to    : Vec<Sender<Vec<Out>>>,
-               dispatcher_type : Option<DispatcherType>) 
-               
+    pub fn new(proc            : Box<dyn ProducerConsumer<In, Out, Error = E> + Send>,
+               subscribe_to    : Vec<Subscription<Out>>,
+               dispatcher_type : Option<DispatcherType<Out>>)
+
     ->  Result<Self, Status>
-    
+
     {
 
         // Check subscribe_to not be empty
-        if subscribe_to.len() == 0 {
+        if subscribe_to.is_empty() {
             return Err(Status::SenderNotFound);
         }
 
         // if dispatcher_type is None, set RoundRobin
-        let dt = if let Some(dt) = dispatcher_type { dt } 
+        let dt = if let Some(dt) = dispatcher_type { dt }
                                 else { DispatcherType::RoundRobin };
-        
-        
+
+
         // Check subscribe_to not have duplicate sender
-        match Dispatcher::new(subscribe_to, dt) {
-            Ok(dispatcher) => {
+        let dispatcher = Dispatcher::new(subscribe_to, dt)?;
 
-                Ok(Self {
-                    proc,
-                    dispatcher
-                })        
-            }
-            Err(a) => {
-                Err(a)
-            }
+        Ok(Self {
+            name: String::from("producer_consumer"),
+            proc,
+            dispatcher,
+            on_error: OnError::Stop,
+            metrics: None,
+            dead_letter: None,
+            retry: None,
+            min_demand: subscription::DEFAULT_MIN_DEMAND,
+            max_demand: subscription::DEFAULT_MAX_DEMAND,
+            tick: None,
+            token: None
+        })
+    }
+
+
+    /// set demand asked from upstream
+    ///
+    /// first ask max_demand events, then every time
+    /// outstanding demand fall to min_demand ask again up to max_demand
+    pub fn with_demand(mut self, min_demand: usize, max_demand: usize) -> Result<Self, Status> {
+        if !subscription::check_demand(min_demand, max_demand) {
+            return Err(Status::InvalidDemand);
         }
-        
+
+        self.min_demand = min_demand;
+        self.max_demand = max_demand;
+        Ok(self)
+    }
+
+
+    /// name of stage, default is its kind
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+
+    /// track stage by pipeline shutdown, `Shutdown::shutdown`
+    /// wait until this stage drained its channel and terminated
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.token = Some(shutdown.token());
+        self
+    }
+
+
+
+    /// record stage metrics to sink
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+
+    /// run by supervisor, when all subscribers stopped wait
+    /// grace period for restarted one instead of stop
+    pub(crate) fn supervised(mut self, grace: Duration) -> Self {
+        self.dispatcher.supervise(grace);
+        self
+    }
+
+
+    /// send events returned from take_failed to destination,
+    /// without it failed events dropped
+    pub fn with_dead_letter(mut self, to: impl Into<DeadLetterTo<In>>) -> Self {
+        self.dead_letter = Some(to.into());
+        self
+    }
+
+
+    /// call handle_tick every interval, even when no events arrive
+    pub fn with_tick(mut self, interval: Duration) -> Self {
+        self.tick = Some(interval);
+        self
+    }
+
+
+
+    /// set what to do when handle_events failed, default OnError::Stop
+    pub fn with_on_error(mut self, on_error: OnError<E>) -> Self {
+        self.on_error = on_error;
+        self
     }
 
 
 
     /// produce events and send to dst/subscribe_to by dispatcher
+    ///
+    /// return Err(reason) if stage must stop
     #[inline]
-    pub async fn produce_to_dst(&mut self, upstream_events: Vec<In>) -> Result<(), DestinationDown<Out>> {
-        let events = self.proc.handle_events(upstream_events).await;
-        self.dispatcher.dispatch(events).await
+    pub async fn produce_to_dst(&mut self, upstream_events: Vec<In>) -> Result<(), ExitReason<Out, E>> {
+        let (events, err, failed) = self.handle_events(upstream_events).await;
+
+        self.dead_letter(failed).await;
+
+        // events of succeeded attempts sent even if last one failed
+        self.emit(Ok(events)).await?;
+
+        match err {
+            Some(err) => self.emit(Err(err)).await,
+            None => Ok(())
+        }
     }
 
-    
 
+    /// call handle_events, again by Retry policy while it failed,
+    /// return events produced by all attempts, error of last attempt
+    /// and events failed for good
+    async fn handle_events(&mut self, upstream_events: Vec<In>) -> (Vec<Out>, Option<E>, Vec<Failed<In>>) {
+        let mut batch = upstream_events;
+        let mut attempt = 1;
+
+        let mut produced = Vec::new();
+
+        // failed events not retried anymore
+        let mut dead = Vec::new();
+
+        let res = loop {
+            let copy = self.retry.as_ref().and_then(|retry| retry.copy(&batch, attempt));
+
+            let len = batch.len();
+            let start = Instant::now();
+            let res = self.proc.handle_events(batch).in_call("handle_events", len).await;
+            metrics::record(&self.metrics, &self.name, Metric::Handler(start.elapsed()));
+
+            let mut failed = self.proc.take_failed();
+
+            let Some(retry) = self.retry.as_ref().filter(|retry| retry.policy.again(attempt)) else {
+                dead.append(&mut failed);
+                break res
+            };
+
+            batch = match (res, copy) {
 
+                // whole batch again, its per-event failures dropped
+                (Err(err), Some(copy)) if retry.policy.is_retryable(&err) => copy,
+
+                // only failed subset again
+                (Ok(mut events), _) if failed.iter().any(|failed| failed.retryable) => {
+                    produced.append(&mut events);
+
+                    let (again, mut fatal): (Vec<_>, Vec<_>) = failed
+                        .into_iter()
+                        .partition(|failed| failed.retryable);
+
+                    dead.append(&mut fatal);
+                    again.into_iter().map(|failed| failed.event).collect()
+                }
+
+                (res, _) => {
+                    dead.append(&mut failed);
+                    break res
+                }
+            };
+
+            event!(warn, attempt, "retry");
+
+            tokio::time::sleep(retry.policy.delay(attempt)).await;
+            attempt += 1;
+        };
+
+        match res {
+            Ok(mut events) => {
+                produced.append(&mut events);
+                (produced, None, dead)
+            }
+            Err(err) => (produced, Some(err), dead)
+        }
+    }
+
+
+    /// record failed events and send them to dead letter
+    async fn dead_letter(&mut self, failed: Vec<Failed<In>>) {
+        if failed.is_empty() {
+            return
+        }
+
+        metrics::record(&self.metrics, &self.name, Metric::Failed(failed.len()));
+        dead_letter::route(&mut self.dead_letter, &self.name, failed).await;
+    }
+
+
+    /// send callback result to dst/subscribe_to, empty batch not dispatched,
+    /// failed events of callback to dead letter
+    #[inline]
+    async fn emit(&mut self, res: Result<Vec<Out>, E>) -> Result<(), ExitReason<Out, E>> {
+        let failed = self.proc.take_failed();
+        self.dead_letter(failed).await;
+
+        let events = match res {
+            Ok(events) => events,
+            Err(err) => {
+                event!(warn, "callback failed");
+
+                return self.on_error
+                    .apply(StageError::Handle(err))
+                    .map_err(ExitReason::Failed)
+            }
+        };
+
+        if events.is_empty() {
+            return Ok(())
+        }
+
+        metrics::record(&self.metrics, &self.name, Metric::EventsOut(events.len()));
+
+        let start = Instant::now();
+        let len = events.len();
+        let res = self.dispatcher.dispatch(events).in_call("dispatch", len).await;
+        metrics::record(&self.metrics, &self.name, Metric::DispatchWait(start.elapsed()));
+
+        res.map_err(|dd| ExitReason::DestinationDown(dd.0))
+    }
+
+
+
+
+    /// run stage, return subscription for upstream, stage handle to
+    /// await its ExitReason and dispatcher handle to subscribe / unsubscribe at runtime
     #[inline]
-    pub fn run(mut self, buffer: usize) -> Sender<Vec<In>> {
-        let (sx, mut rx) = channel(buffer);
+    pub fn run(mut self, buffer: usize) -> (Subscription<In>, StageHandle<Out, E>, DispatcherHandle<Out>) {
+        let (sx, mut rx) = channel::<Vec<In>>(buffer);
+        let demand = Arc::new(Demand::new());
+        let handle = self.dispatcher.handle();
+
+        let mut asker = Asker::new(demand.clone(), self.min_demand, self.max_demand);
+
+        let subscription = Subscription::new(sx, demand);
+        let counter = subscription.counter();
+
+        let name = self.name.clone();
+        let token = self.token.clone();
+
+        let stage = async move {
+
+            if let Err(err) = self.proc.init().await {
+                return ExitReason::Failed(StageError::Init(err))
+            }
 
-        tokio::spawn(async move {
-            
-            self.proc.init().await;
+            event!(info, "init");
+
+            let mut ticker = stage::ticker(self.tick);
 
             loop {
 
-                // Listen on channel
-                match rx.recv().await {
+                // Listen on channel, until tick or stage deadline
+                let deadline = self.proc.deadline();
+
+                let recv = tokio::select! {
+                    recv = rx.recv() => recv,
+                    _ = stage::next_tick(&mut ticker) => {
+                        let res = self.proc.handle_tick().in_call("handle_tick", 0).await;
+                        if let Err(reason) = self.emit(res).await {
+                            return reason
+                        }
+
+                        continue;
+                    }
+                    _ = stage::sleep_until(deadline) => {
+                        let res = self.proc.handle_deadline().in_call("handle_deadline", 0).await;
+                        if let Err(reason) = self.emit(res).await {
+                            return reason
+                        }
+
+                        continue;
+                    }
+                };
+
+                match recv {
                     Some(upstream_events) => {
+                        let len = subscription::count(&counter, &upstream_events);
+
+                        metrics::record(&self.metrics, &self.name, Metric::EventsIn(len));
+                        metrics::record(&self.metrics, &self.name, Metric::Batch(len));
+                        metrics::record(&self.metrics, &self.name, Metric::Queue { len: rx.len(), capacity: buffer });
 
                         // produce events and dispatch
-                        match self.produce_to_dst(upstream_events).await {
-                            Err(dd) => return Some(dd),
-                            Ok(_) => (),
-                        }              
-                        
+                        if let Err(reason) = self.produce_to_dst(upstream_events).await {
+                            return reason
+                        }
+
+                        // downstream got events, ask more from upstream
+                        asker.consumed(len);
                     }
                     None => {
-                        // upstream terminate
-                        self.proc.terminate().await;
-                        return None
+                        // upstream terminate, send held events then terminate
+                        let res = self.proc.drain().in_call("drain", 0).await;
+                        if let Err(reason) = self.emit(res).await {
+                            return reason
+                        }
+
+                        if let Err(err) = self.proc.terminate().await {
+                            return ExitReason::Failed(StageError::Terminate(err))
+                        }
+
+                        event!(info, "terminate");
+
+                        return ExitReason::UpstreamClosed
                     }
                 }
-  
+
             }
-        });
+        };
+
+        let join = tokio::spawn(trace::exited(stage).in_stage(&name, "producer_consumer"));
+
+        if let Some(token) = token {
+            token.track(join.abort_handle());
+        }
 
-        sx
+        (subscription, StageHandle::new(join), handle)
     }
 }
 
+
+
+impl<In, Out, E> ProducerConsumerRunnable<In, Out, E>
+where
+    In:  Clone + Send + 'static,
+    Out: Send + 'static,
+    E:   Send + 'static
+{
+    /// call handle_events again by policy when it failed, see `Retry`
+    ///
+    /// every batch cloned before every attempt except last one,
+    /// also when attempt succeed, events must be Clone
+    pub fn with_retry(mut self, retry: Retry<E>) -> Self {
+        self.retry = Some(Retrying::new(retry));
+        self
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+
+
+/// Retry policy of handle_events, set by runnables `with_retry`
+///
+/// runner call handle_events again with same batch if it returned
+/// retryable error, or with failed subset if it reported retryable
+/// per-event failures by take_failed, waiting backoff between attempts
+///
+/// when attempts exhausted error go to OnError
+/// and failed events to dead letter
+///
+/// runner clone every batch before every attempt except last one
+/// to have it for next one, also when attempt succeed, so retry need
+/// `Clone` events and cost a clone per batch, `Message<T>`
+/// is not Clone and can not be retried
+///
+/// ```rust,ignore
+/// let (sink_chan, _) = ConsumerRunnable::new(Box::new(Sqlite))
+///                     .with_retry(Retry::new(5)
+///                                 .backoff(Duration::from_millis(50), Duration::from_secs(2))
+///                                 .retryable(|err: &DbError| err.is_busy()))
+///                     .run(100);
+/// ```
+pub struct Retry<E> {
+    max_attempts : u32,
+    min          : Duration,
+    max          : Duration,
+    retryable    : Box<dyn Fn(&E) -> bool + Send>
+}
+
+
+impl<E> Retry<E> {
+
+    /// max_attempts include first call, every error is retryable,
+    /// backoff from 10ms up to 1s
+    pub fn new(max_attempts: u32) -> Self {
+        Retry {
+            max_attempts: max_attempts.max(1),
+            min: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+            retryable: Box::new(|_| true)
+        }
+    }
+
+    /// wait between attempts, start from min and double
+    /// after every attempt up to max, with jitter
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+
+    /// classify errors, false is fatal and never retried
+    pub fn retryable<F>(mut self, retryable: F) -> Self
+    where
+        F: Fn(&E) -> bool + Send + 'static
+    {
+        self.retryable = Box::new(retryable);
+        self
+    }
+
+
+    #[inline]
+    pub(crate) fn is_retryable(&self, err: &E) -> bool {
+        (self.retryable)(err)
+    }
+
+    /// attempt is the one failed, first call is 1
+    #[inline]
+    pub(crate) fn again(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    /// wait after failed attempt, jitter between delay/2 and delay
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.min
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max);
+
+        let half = delay / 2;
+        half + half.mul_f64(fastrand::f64())
+    }
+}
+
+
+
+/// Retry policy of a runnable, with copy of batch
+/// kept to call handle_events again
+pub(crate) struct Retrying<In, E> {
+    pub(crate) policy : Retry<E>,
+    copy              : fn(&[In]) -> Vec<In>
+}
+
+
+impl<In: Clone, E> Retrying<In, E> {
+
+    pub(crate) fn new(policy: Retry<E>) -> Self {
+        Retrying {
+            policy,
+            copy: <[In]>::to_vec
+        }
+    }
+}
+
+
+impl<In, E> Retrying<In, E> {
+
+    /// copy of batch if it can be retried after attempt
+    #[inline]
+    pub(crate) fn copy(&self, batch: &[In], attempt: u32) -> Option<Vec<In>> {
+        if !self.policy.again(attempt) {
+            return None
+        }
+
+        Some((self.copy)(batch))
+    }
+}
@@ -0,0 +1,218 @@
+use async_trait::async_trait;
+
+use super::DestinationDown;
+use super::dispatch::{Dispatch, Subscribers};
+use super::trace::event;
+
+
+
+/// What router do with unmatched events,
+/// when not exist any subscriber without selector
+pub enum Unmatched {
+
+    /// drop events
+    Drop,
+
+    /// stop stage, events returned as DestinationDown
+    Fail
+}
+
+
+
+/// Content-based routing dispatcher, subscribers with selector
+/// (`Subscription::with_selector`) are routes and every event
+/// go to subscriber its selector matched
+///
+/// `Router::new` send event to first matching subscriber,
+/// `Router::all_matches` to every matching subscriber,
+/// routes checked in order subscribed
+///
+/// unmatched events, and events of stopped subscribers, go to first
+/// subscriber without selector, if not exist handled by Unmatched
+///
+/// route is part of subscription, subscriber subscribed again by
+/// DispatcherHandle or restarted by supervisor (`Link::with_selector`)
+/// keep its route
+///
+/// ```rust,ignore
+/// // errors go to alerting, everything else go to storage
+/// let alert_chan = alert_chan.with_selector(|log: &LogEvent| log.level == Level::Error);
+///
+/// let (_producer, _) = ProducerRunnable::new(Box::new(Logs),
+///                                            vec![alert_chan, store_chan],
+///                                            Some(DispatcherType::custom(Router::new())),
+///                                            100,
+///                                            shutdown.producer()).unwrap().run();
+/// ```
+pub struct Router<Out> {
+    subscribers : Subscribers<Out>,
+    unmatched   : Unmatched,
+
+    // set by all_matches, clone event for every matching route
+    clone       : Option<fn(&Out) -> Out>
+}
+
+
+impl<Out> Default for Router<Out> {
+    fn default() -> Self {
+        Router {
+            subscribers: Subscribers::new(),
+            unmatched: Unmatched::Drop,
+            clone: None
+        }
+    }
+}
+
+
+impl<Out: Clone> Router<Out> {
+
+    /// router send every event to all matching routes,
+    /// every route except last get a clone of event
+    pub fn all_matches() -> Self {
+        Router {
+            clone: Some(Out::clone),
+            ..Router::default()
+        }
+    }
+}
+
+
+impl<Out> Router<Out> {
+
+    /// router send every event to first matching route
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// what to do with unmatched events when not exist
+    /// subscriber without selector, default Unmatched::Drop
+    pub fn unmatched(mut self, unmatched: Unmatched) -> Self {
+        self.unmatched = unmatched;
+        self
+    }
+
+
+    /// ids of running routes matched event
+    fn matches(&self, event: &Out) -> Vec<u64> {
+        let mut ids = Vec::new();
+
+        let routes = self.subscribers
+            .iter()
+            .filter(|sub| sub.is_selective() && !sub.is_closed());
+
+        for sub in routes {
+            if sub.selects(event) {
+                ids.push(sub.id());
+
+                if self.clone.is_none() {
+                    break;
+                }
+            }
+        }
+
+        ids
+    }
+
+
+    /// send events to subscriber with this id,
+    /// return Err with events not sent
+    async fn send_to(&mut self, id: u64, events: Vec<Out>) -> Result<(), Vec<Out>> {
+        match self.subscribers.position(id) {
+            Some(index) => self.subscribers.send(index, events).await.map_err(|dd| dd.0),
+            None => Err(events)
+        }
+    }
+
+
+    /// send unmatched events to first running subscriber
+    /// without selector, if not exist handle by Unmatched
+    async fn fallback(&mut self, mut events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
+        while !events.is_empty() {
+
+            let default = self.subscribers
+                .iter()
+                .position(|sub| !sub.is_selective() && !sub.is_closed());
+
+            match (default, &self.unmatched) {
+
+                // stopped subscriber removed by send, try next one
+                (Some(index), _) => match self.subscribers.send(index, events).await {
+                    Ok(()) => return Ok(()),
+                    Err(DestinationDown(unsent)) => events = unsent
+                },
+
+                (None, Unmatched::Drop) => {
+                    event!(warn, dropped = events.len(), "unmatched events dropped");
+                    return Ok(())
+                }
+
+                (None, Unmatched::Fail) => return Err(DestinationDown(events))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+#[async_trait]
+impl<Out: Send> Dispatch<Out> for Router<Out> {
+
+    fn subscribers(&mut self) -> &mut Subscribers<Out> {
+        &mut self.subscribers
+    }
+
+    fn name(&self) -> &'static str {
+        "Router"
+    }
+
+
+    /// split events by matching routes, order of
+    /// events of every subscriber is kept
+    async fn dispatch(&mut self, events: Vec<Out>) -> Result<(), DestinationDown<Out>> {
+        self.subscribers.remove_closed();
+
+        if self.subscribers.is_empty() {
+            return Err(DestinationDown(events))
+        }
+
+        let mut routed: Vec<(u64, Vec<Out>)> = Vec::new();
+        let mut unmatched = Vec::new();
+
+        for event in events {
+            let ids = self.matches(&event);
+
+            let Some((last, rest)) = ids.split_last() else {
+                unmatched.push(event);
+                continue;
+            };
+
+            // clone for every route except last, only set by all_matches
+            if let Some(clone) = self.clone {
+                for id in rest {
+                    push(&mut routed, *id, clone(&event));
+                }
+            }
+
+            push(&mut routed, *last, event);
+        }
+
+        // events of stopped subscribers are unmatched
+        for (id, events) in routed {
+            if let Err(mut events) = self.send_to(id, events).await {
+                unmatched.append(&mut events);
+            }
+        }
+
+        self.fallback(unmatched).await
+    }
+}
+
+
+
+fn push<Out>(routed: &mut Vec<(u64, Vec<Out>)>, id: u64, event: Out) {
+    match routed.iter_mut().find(|(rid, _)| *rid == id) {
+        Some((_, events)) => events.push(event),
+        None => routed.push((id, vec![event]))
+    }
+}
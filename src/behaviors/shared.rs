@@ -0,0 +1,140 @@
+use std::ops::Deref;
+use std::sync::Arc;
+
+
+
+/// Batch of events shared by every subscriber of BroadcastShared
+/// dispatcher, clone or split of it only copy pointer
+///
+/// demand of stages get shared batches counted by events
+/// in batches, not by batches
+///
+/// ```rust,ignore
+/// // producer emit its events as one shared batch
+/// async fn handle_demand(&mut self, demand: usize) -> Result<Emit<Shared<LogEvent>>, ()> {
+///     let logs = self.read(demand).await;
+///     Ok(Emit::Events(vec![Shared::from(logs)]))
+/// }
+///
+/// let (_producer, _) = ProducerRunnable::new(Box::new(Logs),
+///                                            vec![store_chan, alert_chan, audit_chan],
+///                                            Some(DispatcherType::broadcast_shared()),
+///                                            100,
+///                                            shutdown.producer()).unwrap().run();
+/// ```
+pub struct Shared<T> {
+    batch : Arc<[T]>,
+    start : usize,
+    end   : usize
+}
+
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared {
+            batch: self.batch.clone(),
+            start: self.start,
+            end: self.end
+        }
+    }
+}
+
+
+impl<T> From<Vec<T>> for Shared<T> {
+    fn from(events: Vec<T>) -> Self {
+        let end = events.len();
+
+        Shared {
+            batch: events.into(),
+            start: 0,
+            end
+        }
+    }
+}
+
+
+impl<T> Deref for Shared<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.batch[self.start..self.end]
+    }
+}
+
+
+impl<T> Shared<T> {
+
+    /// split batch at `at`, return events after it,
+    /// both halves share same batch
+    pub(crate) fn split_off(&mut self, at: usize) -> Shared<T> {
+        let at = (self.start + at).min(self.end);
+
+        let rest = Shared {
+            batch: self.batch.clone(),
+            start: at,
+            end: self.end
+        };
+
+        self.end = at;
+        rest
+    }
+
+    /// events in shared batches, demand they count
+    pub(crate) fn count(batches: &[Shared<T>]) -> usize {
+        batches.iter().map(|shared| shared.len()).sum()
+    }
+
+    /// take shared batches of at maximum `max` events from
+    /// front of `batches`, batch crossing `max` split
+    pub(crate) fn take(batches: &mut Vec<Shared<T>>, max: usize) -> Vec<Shared<T>> {
+        let mut count = 0;
+        let mut at = 0;
+
+        while at < batches.len() && count + batches[at].len() <= max {
+            count += batches[at].len();
+            at += 1;
+        }
+
+        let mut rest = batches.split_off(at);
+
+        if let Some(first) = rest.first_mut() {
+            if count < max {
+                let tail = first.split_off(max - count);
+                batches.push(std::mem::replace(first, tail));
+            }
+        }
+
+        std::mem::replace(batches, rest)
+    }
+}
+
+
+
+/// How BroadcastShared dispatcher copy, count and split
+/// shared batches, create it by `DispatcherType::broadcast_shared`
+pub struct Sharing<Out> {
+    pub(crate) clone : fn(&[Out]) -> Vec<Out>,
+    pub(crate) count : fn(&[Out]) -> usize,
+    pub(crate) take  : fn(&mut Vec<Out>, usize) -> Vec<Out>
+}
+
+
+impl<Out> Clone for Sharing<Out> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Out> Copy for Sharing<Out> {}
+
+
+impl<T> Sharing<Shared<T>> {
+
+    pub(crate) fn new() -> Self {
+        Sharing {
+            clone: <[Shared<T>]>::to_vec,
+            count: Shared::count,
+            take: Shared::take
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::AbortHandle;
+
+use crate::Status;
+
+
+
+/// Pipeline-wide graceful shutdown
+///
+/// stop all producers, then every ProducerConsumer / Consumer
+/// drain its channel and call terminate, shutdown resolve
+/// when whole pipeline stopped
+///
+/// stages not stopped before deadline aborted, like
+/// `SupervisorHandle::shutdown` do with its children
+///
+/// ```rust,ignore
+/// let mut shutdown = Shutdown::new();
+///
+/// let (log_chan, _) = ConsumerRunnable::new(Box::new(Log))
+///                     .with_shutdown(&shutdown)
+///                     .run(100);
+///
+/// let (_producer, _) = ProducerRunnable::new(Box::new(Prod), vec![log_chan], None, 100, shutdown.producer())
+///                     .unwrap()
+///                     .with_shutdown(&shutdown)
+///                     .run();
+///
+/// shutdown.shutdown(Duration::from_secs(5)).await.unwrap();
+/// ```
+pub struct Shutdown {
+    producers : Vec<oneshot::Sender<()>>,
+
+    // every running stage hold a token,
+    //  when all tokens dropped, pipeline stopped
+    token     : mpsc::Sender<()>,
+    stopped   : mpsc::Receiver<()>,
+
+    // running stages, aborted when deadline elapsed
+    stages    : Stages
+}
+
+
+type Stages = Arc<Mutex<Vec<AbortHandle>>>;
+
+
+/// Held by a running stage, dropped when stage stopped
+#[derive(Clone)]
+pub struct ShutdownToken {
+    _token : mpsc::Sender<()>,
+    stages : Stages
+}
+
+
+impl ShutdownToken {
+
+    /// stage of this token spawned, abort it
+    /// if not stopped before deadline
+    pub(crate) fn track(&self, stage: AbortHandle) {
+        let mut stages = self.stages.lock().unwrap();
+
+        stages.retain(|stage| !stage.is_finished());
+        stages.push(stage);
+    }
+}
+
+
+
+impl Shutdown {
+
+    pub fn new() -> Self {
+        let (token, stopped) = mpsc::channel(1);
+
+        Shutdown {
+            producers: Vec::new(),
+            token,
+            stopped,
+            stages: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    /// register a producer, return shutdown receiver
+    /// to pass to ProducerRunnable::new
+    pub fn producer(&mut self) -> oneshot::Receiver<()> {
+        let (sx, rx) = oneshot::channel();
+        self.producers.push(sx);
+        rx
+    }
+
+    /// token to track a stage, used by runnables `with_shutdown`
+    pub fn token(&self) -> ShutdownToken {
+        ShutdownToken {
+            _token: self.token.clone(),
+            stages: self.stages.clone()
+        }
+    }
+
+
+    /// stop producers and wait until every stage
+    /// drained its channel and terminated
+    ///
+    /// return Err(DeadlineElapsed) if pipeline not stopped before
+    /// deadline, then running stages aborted, events in their
+    /// channels are lost
+    pub async fn shutdown(self, deadline: Duration) -> Result<(), Status> {
+        let Shutdown { producers, token, mut stopped, stages } = self;
+
+        for producer in producers {
+            let _ = producer.send(());
+        }
+
+        drop(token);
+
+        // recv return None when all tokens dropped
+        if tokio::time::timeout(deadline, stopped.recv()).await.is_ok() {
+            return Ok(())
+        }
+
+        for stage in stages.lock().unwrap().drain(..) {
+            stage.abort();
+        }
+
+        // aborted stages dropped their tokens
+        stopped.recv().await;
+
+        Err(Status::DeadlineElapsed)
+    }
+}
+
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
@@ -0,0 +1,148 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use super::error::StageError;
+
+
+
+/// Why a stage stopped
+#[derive(Debug)]
+pub enum ExitReason<T, E> {
+
+    /// stage stopped itself, producer got shutdown / emitted Emit::Done
+    /// or consumer returned State::Terminate
+    Normal,
+
+    /// all upstream senders dropped and channel drained
+    UpstreamClosed,
+
+    /// not exist any destination, Vec<T> is stranded events
+    DestinationDown(Vec<T>),
+
+    /// a callback returned Err
+    Failed(StageError<E>),
+
+    /// a callback panicked, contain panic message
+    Panicked(String),
+
+    /// stage aborted by StageHandle::abort or by
+    /// Shutdown / Supervisor when deadline elapsed
+    Aborted
+}
+
+
+
+
+/// Handle of a running stage
+///
+/// await it to get stage ExitReason
+pub struct StageHandle<T, E> {
+    join: JoinHandle<ExitReason<T, E>>
+}
+
+
+impl<T, E> StageHandle<T, E> {
+
+    pub(crate) fn new(join: JoinHandle<ExitReason<T, E>>) -> Self {
+        StageHandle {
+            join
+        }
+    }
+
+    /// return true if stage stopped
+    pub fn is_finished(&self) -> bool {
+        self.join.is_finished()
+    }
+
+    /// stop stage immediately, events in its channel are lost
+    pub fn abort(&self) {
+        self.join.abort()
+    }
+}
+
+
+impl<T, E> Future for StageHandle<T, E> {
+    type Output = ExitReason<T, E>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.join).poll(cx).map(|res| {
+            match res {
+                Ok(reason) => reason,
+                Err(err) if err.is_panic() => {
+                    let panic = err.into_panic();
+
+                    let msg = if let Some(msg) = panic.downcast_ref::<&str>() {
+                        msg.to_string()
+                    } else if let Some(msg) = panic.downcast_ref::<String>() {
+                        msg.clone()
+                    } else {
+                        String::new()
+                    };
+
+                    ExitReason::Panicked(msg)
+                }
+                Err(_) => ExitReason::Aborted
+            }
+        })
+    }
+}
+
+
+
+
+/// Running stage, type of its events erased
+pub(crate) trait Running: Send {
+
+    /// return true if stage failed, panicked, returned Err or DestinationDown
+    fn poll_exit(&mut self, cx: &mut Context<'_>) -> Poll<bool>;
+
+    fn abort(&self);
+}
+
+
+impl<T: Send, E: Send> Running for StageHandle<T, E> {
+
+    fn poll_exit(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        Pin::new(self).poll(cx).map(|reason| {
+            matches!(reason, ExitReason::Panicked(_) | ExitReason::Failed(_) | ExitReason::DestinationDown(_))
+        })
+    }
+
+    fn abort(&self) {
+        StageHandle::abort(self)
+    }
+}
+
+
+
+
+/// wait until deadline, never resolve if None
+pub(crate) async fn sleep_until(deadline: Option<std::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(Instant::from_std(deadline)).await,
+        None => std::future::pending().await
+    }
+}
+
+
+/// first tick after one period, missed ticks delayed not burst
+pub(crate) fn ticker(period: Option<std::time::Duration>) -> Option<Interval> {
+    period.map(|period| {
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    })
+}
+
+
+/// wait next tick, never resolve if None
+pub(crate) async fn next_tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(interval) => { interval.tick().await; }
+        None => std::future::pending().await
+    }
+}
@@ -0,0 +1,349 @@
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{Sender, WeakSender};
+use tokio::sync::mpsc::error::SendError;
+
+
+
+/// default max_demand a stage ask from upstream
+pub const DEFAULT_MAX_DEMAND: usize = 1000;
+
+/// default min_demand, when outstanding demand fall to it stage ask again
+pub const DEFAULT_MIN_DEMAND: usize = 750;
+
+
+
+
+/// filter of events a subscriber want, see `Subscription::with_selector`
+pub(crate) type Selector<T> = Arc<dyn Fn(&T) -> bool + Send + Sync>;
+
+/// count events of a received batch for demand, set by dispatchers
+/// whose events weigh more than one, e.g. BroadcastShared
+pub(crate) type Counter<T> = Arc<OnceLock<fn(&[T]) -> usize>>;
+
+
+
+/// Handle used by upstream to send events into a stage
+///
+/// every subscription carry demand of that stage,
+/// upstream never send more events than stage asked
+pub struct Subscription<T> {
+    sender   : Sender<Vec<T>>,
+    demand   : Arc<Demand>,
+    counter  : Counter<T>,
+    selector : Option<Selector<T>>
+}
+
+
+impl<T> Clone for Subscription<T> {
+    fn clone(&self) -> Self {
+        Subscription {
+            sender: self.sender.clone(),
+            demand: self.demand.clone(),
+            counter: self.counter.clone(),
+            selector: self.selector.clone()
+        }
+    }
+}
+
+
+impl<T> Subscription<T> {
+
+    pub(crate) fn new(sender: Sender<Vec<T>>, demand: Arc<Demand>) -> Self {
+        Subscription {
+            sender,
+            demand,
+            counter: Arc::new(OnceLock::new()),
+            selector: None
+        }
+    }
+
+    /// stage get only events matched by selector,
+    /// Broadcast / BroadcastStrict dispatchers filter events
+    /// before clone them, Router route events by it,
+    /// other dispatchers ignore selector
+    ///
+    /// ```rust,ignore
+    /// let (errors_chan, _) = ConsumerRunnable::new(Box::new(Alert)).run(100);
+    /// let errors_chan = errors_chan.with_selector(|log: &LogEvent| log.level == Level::Error);
+    /// ```
+    pub fn with_selector<F>(self, selector: F) -> Self
+    where
+        F: Fn(&T) -> bool + Send + Sync + 'static
+    {
+        self.selected_by(Arc::new(selector))
+    }
+
+    #[inline]
+    pub(crate) fn selected_by(mut self, selector: Selector<T>) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    /// return true if subscription has selector
+    #[inline]
+    pub fn is_selective(&self) -> bool {
+        self.selector.is_some()
+    }
+
+    /// return true if stage want event, always true without selector
+    #[inline]
+    pub fn selects(&self, event: &T) -> bool {
+        match &self.selector {
+            Some(selector) => selector(event),
+            None => true
+        }
+    }
+
+    /// return true if both subscription send to same stage
+    pub fn same_channel(&self, other: &Self) -> bool {
+        self.sender.same_channel(&other.sender)
+    }
+
+    /// return true if stage stopped and not get anymore events
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed() || self.demand.is_closed()
+    }
+
+    /// unique id of stage this subscription send to
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.demand.id
+    }
+
+    /// events stage asked but not sent yet
+    pub fn pending_demand(&self) -> usize {
+        self.demand.pending()
+    }
+
+    /// free slots of stage channel, in batches,
+    /// zero if channel full and send wait
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.sender.capacity()
+    }
+
+    /// wait until stage channel has free slot,
+    /// return false if stage stopped
+    pub(crate) async fn reserve(&self) -> bool {
+        self.sender.reserve().await.is_ok()
+    }
+
+    /// take at maximum `max` events from pending demand,
+    /// dispatcher must take demand before send events
+    #[inline]
+    pub fn take_demand(&self, max: usize) -> usize {
+        self.demand.take(max)
+    }
+
+    #[inline]
+    pub(crate) fn demand(&self) -> &Arc<Demand> {
+        &self.demand
+    }
+
+    /// stage count events of received batches by `count`,
+    /// set once by dispatcher before it send any event
+    pub(crate) fn count_by(&self, count: fn(&[T]) -> usize) {
+        let _ = self.counter.set(count);
+    }
+
+    #[inline]
+    pub(crate) fn counter(&self) -> Counter<T> {
+        self.counter.clone()
+    }
+
+    /// send events to stage, wait if its channel is full,
+    /// return Err with events if stage stopped
+    #[inline]
+    pub async fn send(&self, events: Vec<T>) -> Result<(), SendError<Vec<T>>> {
+        self.sender.send(events).await
+    }
+
+    /// weak subscription not keep stage channel open
+    pub(crate) fn downgrade(&self) -> WeakSubscription<T> {
+        WeakSubscription {
+            sender: self.sender.downgrade(),
+            demand: self.demand.clone(),
+            counter: self.counter.clone(),
+            selector: self.selector.clone()
+        }
+    }
+}
+
+
+
+pub(crate) struct WeakSubscription<T> {
+    sender   : WeakSender<Vec<T>>,
+    demand   : Arc<Demand>,
+    counter  : Counter<T>,
+    selector : Option<Selector<T>>
+}
+
+
+impl<T> WeakSubscription<T> {
+
+    /// return None if all subscriptions to stage dropped
+    pub(crate) fn upgrade(&self) -> Option<Subscription<T>> {
+        self.sender.upgrade().map(|sender| {
+            Subscription {
+                sender,
+                demand: self.demand.clone(),
+                counter: self.counter.clone(),
+                selector: self.selector.clone()
+            }
+        })
+    }
+}
+
+
+
+
+/// Demand shared between a stage and every upstream subscribed to it
+///
+/// stage add to pending when ask for events,
+/// upstream take from pending before send events
+pub(crate) struct Demand {
+    id      : u64,
+    pending : AtomicUsize,
+    closed  : AtomicBool,
+    waiters : Mutex<Vec<Arc<Notify>>>
+}
+
+
+impl Demand {
+
+    pub(crate) fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Demand {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            pending: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            waiters: Mutex::new(Vec::new())
+        }
+    }
+
+    /// ask upstream for `n` events more
+    pub(crate) fn ask(&self, n: usize) {
+        self.pending.fetch_add(n, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// take at maximum `max` events from pending demand
+    pub(crate) fn take(&self, max: usize) -> usize {
+        let mut taken = 0;
+
+        let _ = self.pending.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+            taken = pending.min(max);
+            Some(pending - taken)
+        });
+
+        taken
+    }
+
+    #[inline]
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// stage stopped, never ask anymore
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    #[inline]
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// register upstream to get notify when demand arrive
+    pub(crate) fn register(&self, waker: &Arc<Notify>) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if !waiters.iter().any(|w| Arc::ptr_eq(w, waker)) {
+            waiters.push(waker.clone());
+        }
+    }
+
+    pub(crate) fn unregister(&self, waker: &Arc<Notify>) {
+        self.waiters.lock().unwrap().retain(|w| !Arc::ptr_eq(w, waker));
+    }
+
+    fn wake(&self) {
+        for waker in self.waiters.lock().unwrap().iter() {
+            // notify_one store permit if upstream not waiting yet,
+            // so never lose a wakeup
+            waker.notify_one();
+        }
+    }
+}
+
+
+
+
+/// Keep track of demand a stage asked from upstream
+///
+/// first ask `max_demand`, then every time outstanding
+/// demand fall to `min_demand` ask again up to `max_demand`
+///
+/// when dropped (stage stopped or panicked) close demand
+/// to upstream not wait for it
+pub(crate) struct Asker {
+    demand      : Arc<Demand>,
+    min_demand  : usize,
+    max_demand  : usize,
+    outstanding : usize
+}
+
+
+impl Asker {
+
+    pub(crate) fn new(demand: Arc<Demand>, min_demand: usize, max_demand: usize) -> Self {
+        demand.ask(max_demand);
+
+        Asker {
+            demand,
+            min_demand,
+            max_demand,
+            outstanding: max_demand
+        }
+    }
+
+    /// stage handled `n` events, ask more if needed
+    pub(crate) fn consumed(&mut self, n: usize) {
+        self.outstanding = self.outstanding.saturating_sub(n);
+
+        if self.outstanding <= self.min_demand {
+            self.demand.ask(self.max_demand - self.outstanding);
+            self.outstanding = self.max_demand;
+        }
+    }
+}
+
+
+impl Drop for Asker {
+    fn drop(&mut self) {
+        self.demand.close();
+    }
+}
+
+
+
+/// events of received batch counted for demand,
+/// by dispatcher counter if set, otherwise length of batch
+#[inline]
+pub(crate) fn count<T>(counter: &Counter<T>, events: &[T]) -> usize {
+    match counter.get() {
+        Some(count) => count(events),
+        None => events.len()
+    }
+}
+
+
+
+/// Check min_demand / max_demand be valid
+pub(crate) fn check_demand(min_demand: usize, max_demand: usize) -> bool {
+    max_demand > 0 && min_demand < max_demand
+}
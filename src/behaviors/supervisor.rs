@@ -0,0 +1,640 @@
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::Status;
+
+use super::DispatcherHandle;
+use super::consumer::ConsumerRunnable;
+use super::producer::ProducerRunnable;
+use super::producer_consumer::ProducerConsumerRunnable;
+use super::stage::Running;
+use super::trace::event;
+use super::subscription::{Selector, Subscription, WeakSubscription};
+
+
+
+/// default max restarts in period
+const DEFAULT_MAX_RESTARTS: usize = 3;
+
+/// default period of max restarts
+const DEFAULT_PERIOD: Duration = Duration::from_secs(5);
+
+/// upstream of a failed child wait this long for
+/// restarted child to subscribe, before it stop too
+const REWIRE_GRACE: Duration = Duration::from_secs(1);
+
+
+
+
+/// Which children restarted when a child failed
+#[derive(Debug, Clone, Copy)]
+pub enum Strategy {
+
+    /// restart only failed child
+    OneForOne,
+
+    /// restart all children
+    OneForAll,
+
+    /// restart failed child and children added after it
+    ///
+    /// children added downstream first, so children
+    /// after a stage are its upstreams
+    RestForOne
+}
+
+
+
+
+/// Supervisor own stages built from factories
+///
+/// a child failed when it panicked, a callback returned Err or it returned DestinationDown,
+/// supervisor restart it by strategy, and re-wire restarted stage
+/// subscription into its upstream dispatchers, upstreams lost their
+/// only subscriber wait for restarted one instead of stop
+///
+/// supervisor hold input of every stage open until shutdown,
+/// so downstream of a failed child keep running and wait
+/// for restarted one, a child stopped normally (e.g. Emit::Done)
+/// release stages it send to, they drain and stop
+///
+/// if more than max_restarts happen in period, supervisor
+/// abort all children and stop with Err(MaxRestarts)
+///
+/// children must added downstream first (consumers, then
+/// producer_consumers, then producers), every method return
+/// Link used by upstream children to subscribe to it
+///
+/// ```rust,ignore
+/// let mut sup = Supervisor::new(Strategy::OneForOne);
+///
+/// let log = sup.consumer(100, || ConsumerRunnable::new(Box::new(Log)));
+///
+/// let filter = sup.producer_consumer(100, vec![log], |subs| {
+///     ProducerConsumerRunnable::new(Box::new(FilterByAge), subs, None)
+/// });
+///
+/// sup.producer(vec![filter], |subs, shutdown| {
+///     ProducerRunnable::new(Box::new(Prod), subs, None, 100, shutdown)
+/// });
+///
+/// let handle = sup.run();
+/// ```
+pub struct Supervisor {
+    strategy     : Strategy,
+    max_restarts : usize,
+    period       : Duration,
+
+    children     : Vec<Box<dyn Child>>
+}
+
+
+impl Supervisor {
+
+    pub fn new(strategy: Strategy) -> Self {
+        Supervisor {
+            strategy,
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            period: DEFAULT_PERIOD,
+            children: Vec::new()
+        }
+    }
+
+
+    /// set restart intensity, at maximum
+    /// max_restarts restarts in period
+    pub fn with_intensity(mut self, max_restarts: usize, period: Duration) -> Self {
+        self.max_restarts = max_restarts;
+        self.period = period;
+        self
+    }
+
+
+    /// add consumer built by factory
+    pub fn consumer<In, E, F>(&mut self, buffer: usize, factory: F) -> Link<In>
+    where
+        In: Send + 'static,
+        E: Send + 'static,
+        F: FnMut() -> ConsumerRunnable<In, E> + Send + 'static
+    {
+        let link = Link::new();
+
+        self.children.push(Box::new(ConsumerChild {
+            buffer,
+            factory,
+            link: link.clone()
+        }));
+
+        link
+    }
+
+
+    /// add producer_consumer built by factory,
+    /// factory get subscriptions of subscribe_to links
+    pub fn producer_consumer<In, Out, E, F>(&mut self,
+                                         buffer: usize,
+                                         subscribe_to: Vec<Link<Out>>,
+                                         factory: F) -> Link<In>
+    where
+        In: Send + 'static,
+        Out: Send + 'static,
+        E: Send + 'static,
+        F: FnMut(Vec<Subscription<Out>>) -> Result<ProducerConsumerRunnable<In, Out, E>, Status> + Send + 'static
+    {
+        let link = Link::new();
+
+        self.children.push(Box::new(ProducerConsumerChild {
+            buffer,
+            factory,
+            subscribe_to,
+            link: link.clone()
+        }));
+
+        link
+    }
+
+
+    /// add producer built by factory,
+    /// factory get subscriptions of subscribe_to links and
+    /// shutdown receiver used by supervisor to stop producer
+    pub fn producer<Out, E, F>(&mut self, subscribe_to: Vec<Link<Out>>, factory: F)
+    where
+        Out: Send + 'static,
+        E: Send + 'static,
+        F: FnMut(Vec<Subscription<Out>>, oneshot::Receiver<()>) -> Result<ProducerRunnable<Out, E>, Status> + Send + 'static
+    {
+        self.children.push(Box::new(ProducerChild {
+            factory,
+            subscribe_to,
+            shutdown: None
+        }));
+    }
+
+
+    /// start all children and supervise them
+    pub fn run(self) -> SupervisorHandle {
+        let (stop, stop_recv) = oneshot::channel();
+
+        let join = tokio::spawn(self.supervise(stop_recv));
+
+        SupervisorHandle {
+            stop,
+            join
+        }
+    }
+
+
+
+
+    async fn supervise(mut self, mut stop: oneshot::Receiver<Duration>) -> Result<(), Status> {
+        let mut running: Vec<Option<Box<dyn Running>>> = Vec::new();
+        running.resize_with(self.children.len(), || None);
+
+        self.start(0, &mut running).await?;
+
+        let mut restarts = VecDeque::new();
+
+        // if SupervisorHandle dropped, never listen on stop again
+        let mut stop_dropped = false;
+
+        loop {
+
+            // wait for first child stopped, or stop request
+            let (index, failed) = tokio::select! {
+                res = &mut stop, if !stop_dropped => {
+                    match res {
+                        Ok(deadline) => return self.shutdown(&mut running, deadline).await,
+                        Err(_) => {
+                            stop_dropped = true;
+                            continue;
+                        }
+                    }
+                }
+                exit = next_exit(&mut running) => exit
+            };
+
+            running[index] = None;
+
+            // child stopped normally, not restart it,
+            // stages it send to stop when drained
+            if !failed {
+                self.children[index].done();
+
+                if running.iter().all(|stage| stage.is_none()) {
+                    return Ok(())
+                }
+
+                continue;
+            }
+
+
+            // check restart intensity
+            let now = Instant::now();
+            restarts.push_back(now);
+
+            while let Some(first) = restarts.front() {
+                if now.duration_since(*first) > self.period {
+                    restarts.pop_front();
+                } else {
+                    break
+                }
+            }
+
+            if restarts.len() > self.max_restarts {
+                event!(error, child = index, "max restarts");
+                abort_all(&mut running).await;
+                return Err(Status::MaxRestarts)
+            }
+
+
+            event!(warn, child = index, strategy = ?self.strategy, "restart");
+
+            // restart children by strategy
+            match self.strategy {
+                Strategy::OneForOne => {
+                    match self.children[index].start(index).await {
+                        Ok(stage) => running[index] = Some(stage),
+                        Err(err) => {
+                            abort_all(&mut running).await;
+                            return Err(err)
+                        }
+                    }
+                }
+                Strategy::OneForAll => {
+                    abort_all(&mut running).await;
+                    self.start(0, &mut running).await?;
+                }
+                Strategy::RestForOne => {
+                    abort_all(&mut running[index..]).await;
+                    self.start(index, &mut running).await?;
+                }
+            }
+        }
+    }
+
+
+    /// start children from index, downstream first
+    async fn start(&mut self, from: usize, running: &mut [Option<Box<dyn Running>>]) -> Result<(), Status> {
+        for index in from..self.children.len() {
+            match self.children[index].start(index).await {
+                Ok(stage) => running[index] = Some(stage),
+                Err(err) => {
+                    abort_all(running).await;
+                    return Err(err)
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+
+    /// stop producers, then wait until every child drained and stopped
+    async fn shutdown(&mut self, running: &mut [Option<Box<dyn Running>>], deadline: Duration) -> Result<(), Status> {
+        for child in self.children.iter_mut() {
+            child.stop();
+        }
+
+        let stopped = async {
+            for stage in running.iter_mut().flatten() {
+                poll_fn(|cx| stage.poll_exit(cx)).await;
+            }
+        };
+
+        if tokio::time::timeout(deadline, stopped).await.is_err() {
+            abort_all(running).await;
+            return Err(Status::DeadlineElapsed)
+        }
+
+        Ok(())
+    }
+}
+
+
+
+
+/// Handle of a running supervisor
+///
+/// await it to get result, Err(MaxRestarts) if restart
+/// intensity exceeded
+pub struct SupervisorHandle {
+    stop : oneshot::Sender<Duration>,
+    join : JoinHandle<Result<(), Status>>
+}
+
+
+impl SupervisorHandle {
+
+    /// stop producers and wait until every child drained
+    /// its channel and terminated, not restart anymore
+    ///
+    /// return Err(DeadlineElapsed) if children not stopped
+    /// before deadline, then they aborted
+    pub async fn shutdown(self, deadline: Duration) -> Result<(), Status> {
+        let SupervisorHandle { stop, join } = self;
+
+        let _ = stop.send(deadline);
+
+        SupervisorHandle::result(join.await)
+    }
+
+    fn result(res: Result<Result<(), Status>, tokio::task::JoinError>) -> Result<(), Status> {
+        match res {
+            Ok(res) => res,
+            Err(_) => Err(Status::StageStopped)
+        }
+    }
+}
+
+
+impl Future for SupervisorHandle {
+    type Output = Result<(), Status>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.join).poll(cx).map(SupervisorHandle::result)
+    }
+}
+
+
+
+
+/// Input of a supervised stage, survive restarts
+///
+/// used by upstream children to get current
+/// subscription of stage
+pub struct Link<T> {
+    inner: Arc<Mutex<LinkInner<T>>>
+}
+
+
+struct LinkInner<T> {
+
+    // held while supervisor running, keep stage channel open
+    //  when its upstreams restarted, dropped by release
+    strong    : Option<Subscription<T>>,
+    weak      : Option<WeakSubscription<T>>,
+
+    // dispatchers of upstream children by child index
+    upstreams : Vec<(usize, DispatcherHandle<T>)>,
+
+    // selector of every subscription of stage, survive restarts
+    selector  : Option<Selector<T>>
+}
+
+
+impl<T> Clone for Link<T> {
+    fn clone(&self) -> Self {
+        Link {
+            inner: self.inner.clone()
+        }
+    }
+}
+
+
+impl<T> Link<T> {
+
+    fn new() -> Self {
+        Link {
+            inner: Arc::new(Mutex::new(LinkInner {
+                strong: None,
+                weak: None,
+                upstreams: Vec::new(),
+                selector: None
+            }))
+        }
+    }
+
+    /// stage get only events matched by selector, set on every
+    /// subscription of stage, also after restart, see
+    /// `Subscription::with_selector`
+    pub fn with_selector<F>(self, selector: F) -> Self
+    where
+        F: Fn(&T) -> bool + Send + Sync + 'static
+    {
+        self.inner.lock().unwrap().selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// current subscription of stage
+    fn subscription(&self) -> Option<Subscription<T>> {
+        let inner = self.inner.lock().unwrap();
+
+        match &inner.strong {
+            Some(sub) => Some(sub.clone()),
+            None => inner.weak.as_ref().and_then(|weak| weak.upgrade())
+        }
+    }
+
+    /// upstream child started, keep its dispatcher
+    /// to subscribe stage when restarted
+    fn register(&self, child: usize, handle: DispatcherHandle<T>) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.upstreams.retain(|(index, _)| *index != child);
+        inner.upstreams.push((child, handle));
+    }
+
+    /// stage (re)started, subscribe it into running upstreams
+    async fn rewire(&self, mut sub: Subscription<T>) {
+        let upstreams: Vec<DispatcherHandle<T>> = {
+            let mut inner = self.inner.lock().unwrap();
+
+            if let Some(selector) = inner.selector.clone() {
+                sub = sub.selected_by(selector);
+            }
+
+            inner.weak = Some(sub.downgrade());
+            inner.strong = Some(sub.clone());
+            inner.upstreams.iter().map(|(_, handle)| handle.clone()).collect()
+        };
+
+        for handle in upstreams {
+            let _ = handle.subscribe(sub.clone()).await;
+        }
+    }
+
+    /// drop strong subscription, stage stop when upstreams stopped
+    fn release(&self) {
+        self.inner.lock().unwrap().strong = None;
+    }
+}
+
+
+fn subscriptions<T>(links: &[Link<T>]) -> Result<Vec<Subscription<T>>, Status> {
+    links.iter()
+         .map(|link| link.subscription().ok_or(Status::SenderNotFound))
+         .collect()
+}
+
+
+
+
+// -----------------------------------------
+
+
+/// wait for first stopped child, return its index
+/// and true if it failed
+fn next_exit(running: &mut [Option<Box<dyn Running>>]) -> impl Future<Output = (usize, bool)> + '_ {
+    poll_fn(move |cx| {
+        for (index, stage) in running.iter_mut().enumerate() {
+            if let Some(stage) = stage {
+                if let Poll::Ready(failed) = stage.poll_exit(cx) {
+                    return Poll::Ready((index, failed))
+                }
+            }
+        }
+
+        Poll::Pending
+    })
+}
+
+
+/// abort children and wait until stopped
+async fn abort_all(running: &mut [Option<Box<dyn Running>>]) {
+    for stage in running.iter_mut() {
+        if let Some(mut stage) = stage.take() {
+            stage.abort();
+            poll_fn(|cx| stage.poll_exit(cx)).await;
+        }
+    }
+}
+
+
+
+
+#[async_trait]
+trait Child: Send {
+
+    /// build and run stage, subscribe it to upstreams
+    async fn start(&mut self, index: usize) -> Result<Box<dyn Running>, Status>;
+
+    /// supervisor shutting down
+    fn stop(&mut self);
+
+    /// child stopped normally, release stages it send to,
+    /// they stop when their other upstreams stopped too
+    fn done(&mut self);
+}
+
+
+struct ConsumerChild<In, F> {
+    buffer  : usize,
+    factory : F,
+    link    : Link<In>
+}
+
+
+#[async_trait]
+impl<In, E, F> Child for ConsumerChild<In, F>
+where
+    In: Send + 'static,
+    E: Send + 'static,
+    F: FnMut() -> ConsumerRunnable<In, E> + Send + 'static
+{
+    async fn start(&mut self, _index: usize) -> Result<Box<dyn Running>, Status> {
+        let (sub, stage) = (self.factory)().run(self.buffer);
+
+        self.link.rewire(sub).await;
+
+        Ok(Box::new(stage))
+    }
+
+    fn stop(&mut self) {
+        self.link.release();
+    }
+
+    fn done(&mut self) {}
+}
+
+
+struct ProducerConsumerChild<In, Out, F> {
+    buffer       : usize,
+    factory      : F,
+    subscribe_to : Vec<Link<Out>>,
+    link         : Link<In>
+}
+
+
+#[async_trait]
+impl<In, Out, E, F> Child for ProducerConsumerChild<In, Out, F>
+where
+    In: Send + 'static,
+    Out: Send + 'static,
+    E: Send + 'static,
+    F: FnMut(Vec<Subscription<Out>>) -> Result<ProducerConsumerRunnable<In, Out, E>, Status> + Send + 'static
+{
+    async fn start(&mut self, index: usize) -> Result<Box<dyn Running>, Status> {
+        let subs = subscriptions(&self.subscribe_to)?;
+
+        let (sub, stage, handle) = (self.factory)(subs)?.supervised(REWIRE_GRACE).run(self.buffer);
+
+        for link in self.subscribe_to.iter() {
+            link.register(index, handle.clone());
+        }
+
+        self.link.rewire(sub).await;
+
+        Ok(Box::new(stage))
+    }
+
+    fn stop(&mut self) {
+        self.link.release();
+    }
+
+    fn done(&mut self) {
+        for link in self.subscribe_to.iter() {
+            link.release();
+        }
+    }
+}
+
+
+struct ProducerChild<Out, F> {
+    factory      : F,
+    subscribe_to : Vec<Link<Out>>,
+    shutdown     : Option<oneshot::Sender<()>>
+}
+
+
+#[async_trait]
+impl<Out, E, F> Child for ProducerChild<Out, F>
+where
+    Out: Send + 'static,
+    E: Send + 'static,
+    F: FnMut(Vec<Subscription<Out>>, oneshot::Receiver<()>) -> Result<ProducerRunnable<Out, E>, Status> + Send + 'static
+{
+    async fn start(&mut self, index: usize) -> Result<Box<dyn Running>, Status> {
+        let subs = subscriptions(&self.subscribe_to)?;
+        let (shutdown, shutdown_recv) = oneshot::channel();
+
+        let (stage, handle) = (self.factory)(subs, shutdown_recv)?.supervised(REWIRE_GRACE).run();
+
+        for link in self.subscribe_to.iter() {
+            link.register(index, handle.clone());
+        }
+
+        self.shutdown = Some(shutdown);
+
+        Ok(Box::new(stage))
+    }
+
+    fn stop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+
+    fn done(&mut self) {
+        for link in self.subscribe_to.iter() {
+            link.release();
+        }
+    }
+}
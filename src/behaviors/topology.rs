@@ -0,0 +1,152 @@
+use std::fmt::Write;
+
+
+
+/// Kind of stage in topology
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StageKind {
+    Producer,
+    ProducerConsumer,
+    Consumer
+}
+
+
+impl StageKind {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StageKind::Producer => "Producer",
+            StageKind::ProducerConsumer => "ProducerConsumer",
+            StageKind::Consumer => "Consumer"
+        }
+    }
+}
+
+
+
+/// Stage in topology, concurrency is copies of it
+#[derive(Debug, Clone)]
+pub struct StageInfo {
+    pub name        : String,
+    pub kind        : StageKind,
+    pub concurrency : usize,
+
+    /// channel buffer, None for producer
+    pub buffer      : Option<usize>
+}
+
+
+/// Edge between stages, from / to are index of stages
+#[derive(Debug, Clone)]
+pub struct Edge {
+    pub from       : usize,
+    pub to         : usize,
+    pub dispatcher : &'static str
+}
+
+
+
+/// Graph of stages, kept by Pipeline or built by hand
+/// for pipelines wired by runnables
+///
+/// ```rust,ignore
+/// let pipeline = Pipeline::from(Prod).name("prod")
+///                     .via(|| FilterByAge, 4).name("filter")
+///                     .to_named("log", || Log, 2)
+///                     .unwrap();
+///
+/// std::fs::write("pipeline.dot", pipeline.topology().to_dot()).unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Topology {
+    pub stages : Vec<StageInfo>,
+    pub edges  : Vec<Edge>
+}
+
+
+
+impl Topology {
+
+    pub fn new() -> Self {
+        Topology::default()
+    }
+
+    /// add stage, return its index
+    pub fn stage(&mut self, name: impl Into<String>, kind: StageKind, concurrency: usize, buffer: Option<usize>) -> usize {
+        self.stages.push(StageInfo {
+            name: name.into(),
+            kind,
+            concurrency,
+            buffer
+        });
+
+        self.stages.len() - 1
+    }
+
+    /// add edge, dispatcher is `DispatcherType::name` of from stage
+    pub fn edge(&mut self, from: usize, to: usize, dispatcher: &'static str) {
+        self.edges.push(Edge {
+            from,
+            to,
+            dispatcher
+        })
+    }
+
+
+    /// Graphviz DOT text
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph pipeline {\n    rankdir=LR;\n    node [shape=box];\n\n");
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            let _ = writeln!(dot,
+                             "    s{} [label=\"{}\"];",
+                             index,
+                             label(stage, "\\n").replace('"', "\\\""));
+        }
+
+        dot.push('\n');
+
+        for edge in self.edges.iter() {
+            let _ = writeln!(dot, "    s{} -> s{} [label=\"{}\"];", edge.from, edge.to, edge.dispatcher);
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+
+    /// Mermaid flowchart text
+    pub fn to_mermaid(&self) -> String {
+        let mut mermaid = String::from("flowchart LR\n");
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            let _ = writeln!(mermaid,
+                             "    s{}[\"{}\"]",
+                             index,
+                             label(stage, "<br/>").replace('"', "#quot;"));
+        }
+
+        for edge in self.edges.iter() {
+            let _ = writeln!(mermaid, "    s{} -->|{}| s{}", edge.from, edge.dispatcher, edge.to);
+        }
+
+        mermaid
+    }
+}
+
+
+
+/// name, kind x concurrency, buffer
+fn label(stage: &StageInfo, newline: &str) -> String {
+    let mut label = format!("{}{}{}", stage.name, newline, stage.kind.name());
+
+    if stage.concurrency != 1 {
+        let _ = write!(label, " x{}", stage.concurrency);
+    }
+
+    if let Some(buffer) = stage.buffer {
+        let _ = write!(label, "{}buffer {}", newline, buffer);
+    }
+
+    label
+}
@@ -0,0 +1,86 @@
+// `tracing` integration, enabled by `tracing` feature,
+// without feature spans and events compiled to nothing
+
+use std::future::Future;
+
+use super::stage::ExitReason;
+
+
+
+/// structured lifecycle event, `event!(warn, stranded = n, "destination down")`
+#[cfg(feature = "tracing")]
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        tracing::$level!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        ()
+    };
+}
+
+pub(crate) use event;
+
+
+
+/// run future inside span
+pub(crate) trait Traced: Future + Sized {
+
+    /// span of whole stage task
+    #[cfg(feature = "tracing")]
+    fn in_stage(self, name: &str, kind: &'static str) -> tracing::instrument::Instrumented<Self> {
+        use tracing::Instrument;
+        self.instrument(tracing::info_span!("stage", name, kind))
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn in_stage(self, _name: &str, _kind: &'static str) -> Self {
+        self
+    }
+
+
+    /// child span of callback / dispatch call, with batch size
+    #[cfg(feature = "tracing")]
+    fn in_call(self, call: &'static str, batch: usize) -> tracing::instrument::Instrumented<Self> {
+        use tracing::Instrument;
+        self.instrument(tracing::debug_span!("call", call, batch))
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn in_call(self, _call: &'static str, _batch: usize) -> Self {
+        self
+    }
+}
+
+
+impl<F: Future> Traced for F {}
+
+
+
+/// run stage task, emit event of its exit reason
+pub(crate) async fn exited<T, E, F>(stage: F) -> ExitReason<T, E>
+where
+    F: Future<Output = ExitReason<T, E>>
+{
+    let reason = stage.await;
+
+    #[cfg(feature = "tracing")]
+    {
+        use super::error::StageError;
+
+        match &reason {
+            ExitReason::Normal => tracing::info!("stopped"),
+            ExitReason::UpstreamClosed => tracing::info!("upstream closed"),
+            ExitReason::DestinationDown(events) => tracing::warn!(stranded = events.len(), "destination down"),
+            ExitReason::Failed(StageError::Init(_)) => tracing::error!(callback = "init", "failed"),
+            ExitReason::Failed(StageError::Handle(_)) => tracing::error!(callback = "handle", "failed"),
+            ExitReason::Failed(StageError::Terminate(_)) => tracing::error!(callback = "terminate", "failed"),
+            ExitReason::Panicked(_) | ExitReason::Aborted => ()
+        }
+    }
+
+    reason
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+use super::producer_consumer::ProducerConsumer;
+
+
+
+/// Time range of a window, [start, end) since UNIX_EPOCH
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start : Duration,
+    pub end   : Duration
+}
+
+
+
+type Aggregate<K, In, Out> = Box<dyn FnMut(&K, Span, Vec<In>) -> Out + Send>;
+
+
+enum Kind<In> {
+    Tumbling(Duration),
+
+    // size, slide, clone event for every window it belong to
+    Sliding(Duration, Duration, fn(&In) -> In),
+
+    // gap
+    Session(Duration)
+}
+
+
+enum Time<In> {
+    Processing,
+    Event {
+        timestamp : Box<dyn Fn(&In) -> Duration + Send + Sync>,
+        lateness  : Duration
+    }
+}
+
+
+
+/// Built-in ProducerConsumer, assign events to windows per key
+/// and send aggregate of every window to next stage when window closed
+///
+/// window closed by timer even when no new events arrive,
+/// open windows closed when upstream closed
+///
+/// * processing-time (default) window closed when wall clock passed its end
+/// * event-time window closed when watermark passed its end,
+///   watermark is max event timestamp minus lateness and move forward
+///   with wall clock while no events arrive, late events dropped
+///
+/// wall clock read once when window created, then moved by tokio
+/// clock, so `tokio::time::pause` drive windows in tests
+///
+/// ```rust,ignore
+/// // count clicks per user every minute
+/// let window = Window::tumbling(Duration::from_secs(60),
+///                               |click: &Click| click.user_id,
+///                               |user_id, span, clicks| (*user_id, span, clicks.len()));
+///
+/// let (window_chan, _, _) = ProducerConsumerRunnable::new(Box::new(window), vec![log_chan], None)
+///                             .unwrap()
+///                             .run(100);
+/// ```
+pub struct Window<In, K, Out> {
+    kind      : Kind<In>,
+    time      : Time<In>,
+    key       : Box<dyn Fn(&In) -> K + Send + Sync>,
+    aggregate : Aggregate<K, In, Out>,
+
+    open      : HashMap<K, Vec<(Span, Vec<In>)>>,
+
+    // wall clock when window created, and tokio clock then
+    epoch     : Duration,
+    created   : Instant,
+
+    // event-time watermark, and when it updated
+    watermark : Duration,
+    advanced  : Instant
+}
+
+
+
+impl<In, K, Out> Window<In, K, Out>
+where
+    K: Hash + Eq + Clone
+{
+    /// fixed size, not overlapped windows
+    pub fn tumbling<KF, AF>(size: Duration, key: KF, aggregate: AF) -> Self
+    where
+        KF: Fn(&In) -> K + Send + Sync + 'static,
+        AF: FnMut(&K, Span, Vec<In>) -> Out + Send + 'static
+    {
+        Window::new(Kind::Tumbling(nonzero(size)), Box::new(key), Box::new(aggregate))
+    }
+
+
+    /// windows closed when no events arrived for gap
+    pub fn session<KF, AF>(gap: Duration, key: KF, aggregate: AF) -> Self
+    where
+        KF: Fn(&In) -> K + Send + Sync + 'static,
+        AF: FnMut(&K, Span, Vec<In>) -> Out + Send + 'static
+    {
+        Window::new(Kind::Session(nonzero(gap)), Box::new(key), Box::new(aggregate))
+    }
+
+
+    /// use event-time, timestamp return event time since UNIX_EPOCH,
+    /// lateness is how much events can be out of order
+    pub fn event_time<F>(mut self, timestamp: F, lateness: Duration) -> Self
+    where
+        F: Fn(&In) -> Duration + Send + Sync + 'static
+    {
+        self.time = Time::Event {
+            timestamp: Box::new(timestamp),
+            lateness
+        };
+        self
+    }
+
+
+    fn new(kind      : Kind<In>,
+           key       : Box<dyn Fn(&In) -> K + Send + Sync>,
+           aggregate : Aggregate<K, In, Out>) -> Self
+    {
+        Window {
+            kind,
+            time: Time::Processing,
+            key,
+            aggregate,
+            open: HashMap::new(),
+            epoch: wall_clock(),
+            created: Instant::now(),
+            watermark: Duration::ZERO,
+            advanced: Instant::now()
+        }
+    }
+
+
+    /// current time for closing windows
+    fn now(&self) -> Duration {
+        match self.time {
+            Time::Processing => self.epoch + self.created.elapsed(),
+            Time::Event { .. } => self.watermark + self.advanced.elapsed()
+        }
+    }
+
+
+    /// add event to its windows
+    fn assign(&mut self, event: In) {
+        let now = self.now();
+
+        let ts = match &self.time {
+            Time::Processing => now,
+            Time::Event { timestamp, lateness } => {
+                let ts = timestamp(&event);
+
+                self.watermark = now.max(ts.saturating_sub(*lateness));
+                self.advanced = Instant::now();
+                ts
+            }
+        };
+
+        let now = self.now();
+        let key = (self.key)(&event);
+        let windows = self.open.entry(key).or_default();
+
+        match self.kind {
+            Kind::Tumbling(size) => {
+                let start = floor(ts, size);
+                let span = Span { start, end: start + size };
+
+                // late event
+                if span.end <= now {
+                    return
+                }
+
+                match windows.iter_mut().find(|(s, _)| *s == span) {
+                    Some((_, events)) => events.push(event),
+                    None => windows.push((span, vec![event]))
+                }
+            }
+
+            Kind::Sliding(size, slide, clone) => {
+                let mut spans = Vec::new();
+                let mut start = floor(ts, slide);
+
+                loop {
+                    let span = Span { start, end: start + size };
+                    if span.end <= ts || span.end <= now {
+                        break
+                    }
+
+                    spans.push(span);
+
+                    if start < slide {
+                        break
+                    }
+                    start -= slide;
+                }
+
+                // last window get original event
+                let mut event = Some(event);
+                let len = spans.len();
+
+                for (i, span) in spans.into_iter().enumerate() {
+                    let ev = if i + 1 == len {
+                        event.take().unwrap()
+                    } else {
+                        clone(event.as_ref().unwrap())
+                    };
+
+                    match windows.iter_mut().find(|(s, _)| *s == span) {
+                        Some((_, events)) => events.push(ev),
+                        None => windows.push((span, vec![ev]))
+                    }
+                }
+            }
+
+            Kind::Session(gap) => {
+                let mut span = Span { start: ts, end: ts + gap };
+
+                // late event
+                if span.end <= now {
+                    return
+                }
+
+                // merge every session touched by this event
+                let mut events = vec![event];
+                let mut i = 0;
+                while i < windows.len() {
+                    let (s, _) = &windows[i];
+                    if s.start <= span.end && span.start <= s.end {
+                        let (s, mut evs) = windows.swap_remove(i);
+                        span.start = span.start.min(s.start);
+                        span.end = span.end.max(s.end);
+                        evs.append(&mut events);
+                        events = evs;
+                    } else {
+                        i += 1;
+                    }
+                }
+
+                windows.push((span, events));
+            }
+        }
+    }
+
+
+    /// close windows which ended, or all windows if all is true
+    fn close(&mut self, all: bool) -> Vec<Out> {
+        let now = self.now();
+        let mut closed = Vec::new();
+
+        for (key, windows) in self.open.iter_mut() {
+            let mut i = 0;
+            while i < windows.len() {
+                if all || windows[i].0.end <= now {
+                    let (span, events) = windows.swap_remove(i);
+                    closed.push((key.clone(), span, events));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        self.open.retain(|_, windows| !windows.is_empty());
+
+        closed.sort_by_key(|(_, span, _)| (span.end, span.start));
+        closed.into_iter()
+            .map(|(key, span, events)| (self.aggregate)(&key, span, events))
+            .collect()
+    }
+}
+
+
+
+impl<In, K, Out> Window<In, K, Out>
+where
+    In: Clone,
+    K: Hash + Eq + Clone
+{
+    /// fixed size windows start every slide, event
+    /// belong to size / slide windows
+    pub fn sliding<KF, AF>(size: Duration, slide: Duration, key: KF, aggregate: AF) -> Self
+    where
+        KF: Fn(&In) -> K + Send + Sync + 'static,
+        AF: FnMut(&K, Span, Vec<In>) -> Out + Send + 'static
+    {
+        Window::new(Kind::Sliding(nonzero(size), nonzero(slide), In::clone), Box::new(key), Box::new(aggregate))
+    }
+}
+
+
+
+#[async_trait]
+impl<In, K, Out> ProducerConsumer<In, Out> for Window<In, K, Out>
+where
+    In: Send,
+    K: Hash + Eq + Clone + Send,
+    Out: Send
+{
+    type Error = Infallible;
+
+    async fn init(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, upstream_events: Vec<In>) -> Result<Vec<Out>, Infallible> {
+        for event in upstream_events {
+            self.assign(event);
+        }
+
+        Ok(self.close(false))
+    }
+
+    async fn terminate(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+
+    fn deadline(&self) -> Option<std::time::Instant> {
+        let now = self.now();
+
+        self.open
+            .values()
+            .flatten()
+            .map(|(span, _)| span.end)
+            .min()
+            .map(|end| (Instant::now() + end.saturating_sub(now)).into_std())
+    }
+
+    async fn handle_deadline(&mut self) -> Result<Vec<Out>, Infallible> {
+        Ok(self.close(false))
+    }
+
+    async fn drain(&mut self) -> Result<Vec<Out>, Infallible> {
+        Ok(self.close(true))
+    }
+}
+
+
+
+
+fn wall_clock() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+
+fn floor(ts: Duration, size: Duration) -> Duration {
+    let size = size.as_nanos();
+    Duration::from_nanos((ts.as_nanos() - ts.as_nanos() % size) as u64)
+}
+
+
+fn nonzero(d: Duration) -> Duration {
+    d.max(Duration::from_millis(1))
+}
@@ -1,27 +1,20 @@
-
 pub mod behaviors;
 
-use tokio::sync::{mpsc::Sender, oneshot};
-use std::{time::Duration, collections::HashMap};
-
-use async_trait::async_trait;
-
-
-use tokio::sync::oneshot::channel;
-
-
-
 
 
 /// # Example
 ///
-/// 
-/// ```rust
-/// 
+/// every stage ask demand from its upstream, and upstream
+/// never send more events than asked (back-pressure)
+///
+/// ```rust,no_run
+/// # use std::time::Duration;
+/// # use async_trait::async_trait;
+/// # use last_stage::*;
 /// #[tokio::main]
 /// async fn main() {
 /// 
-///     let(shutdown_sender, shutdown_recv) = channel();
+///     let mut shutdown = Shutdown::new();
 /// 
 /// 
 ///     // -----------------------------------
@@ -33,24 +26,27 @@ use tokio::sync::oneshot::channel;
 /// 
 /// 
 ///     // Run Consumer
-///     let log_chan = ConsumerRunnable::new(Box::new(Log)).run(100);
+///     let (log_chan, _) = ConsumerRunnable::new(Box::new(Log)).with_shutdown(&shutdown).run(100);
 /// 
 /// 
 ///     // Run ProducerConsumer
-///     let filter_chan = ProducerConsumerRunnable::new(Box::new(FilterByAge), 
+///     let (filter_chan, _, _) = ProducerConsumerRunnable::new(Box::new(FilterByAge), 
 ///                                                     vec![log_chan], 
 ///                                                     Some(DispatcherType::RoundRobin)
-///                                                     ).unwrap().run(100);
+///                                                     ).unwrap().with_shutdown(&shutdown).run(100);
 /// 
 ///     // Run Producer
-///     let _ = ProducerRunnable::new(Box::new(Prod), 
+///     let (_producer, _) = ProducerRunnable::new(Box::new(Prod), 
 ///                                   vec![filter_chan], 
 ///                                   None, 
 ///                                   100, 
-///                                   shutdown_recv).unwrap().run();
+///                                   shutdown.producer()).unwrap().with_shutdown(&shutdown).run();
 /// 
 ///     
 ///     tokio::time::sleep(Duration::from_secs(10)).await;
+/// 
+///     // stop producer and wait until all events consumed
+///     shutdown.shutdown(Duration::from_secs(5)).await.unwrap();
 /// }
 /// 
 /// 
@@ -64,13 +60,14 @@ use tokio::sync::oneshot::channel;
 /// 
 /// #[async_trait]
 /// impl Producer<ProdEvent> for Prod {
-///     async fn init(&mut self) {
+///     type Error = ();
 /// 
+///     async fn init(&mut self) -> Result<(), ()> {
+///         Ok(())
 ///     }
 /// 
-///     async fn handle_demand(&mut self, max_demand: usize) -> Vec<ProdEvent> {
-///         (0..max_demand as i32)
-///             .into_iter()
+///     async fn handle_demand(&mut self, max_demand: usize) -> Result<Emit<ProdEvent>, ()> {
+///         Ok(Emit::Events((0..max_demand as i32)
 ///             .map(|i| {
 ///                 
 ///                 ProdEvent { 
@@ -79,11 +76,11 @@ use tokio::sync::oneshot::channel;
 ///                 }
 /// 
 ///             })
-///             .collect()
+///             .collect()))
 ///     }
 /// 
-///     async fn terminate(&mut self) {
-/// 
+///     async fn terminate(&mut self) -> Result<(), ()> {
+///         Ok(())
 ///     }
 /// } 
 /// 
@@ -95,19 +92,21 @@ use tokio::sync::oneshot::channel;
 /// 
 /// #[async_trait]
 /// impl ProducerConsumer<ProdEvent, ProdEvent> for FilterByAge {
-///     async fn init(&mut self) {
-///         
+///     type Error = ();
+/// 
+///     async fn init(&mut self) -> Result<(), ()> {
+///         Ok(())
 ///     }
 /// 
-///     async fn handle_events(&mut self, events: Vec<ProdEvent>) -> Vec<ProdEvent> {
-///         events
+///     async fn handle_events(&mut self, events: Vec<ProdEvent>) -> Result<Vec<ProdEvent>, ()> {
+///         Ok(events
 ///             .into_iter()
 ///             .filter(|pe| pe.age > 25 && pe.age < 32)
-///             .collect()
+///             .collect())
 ///     }
 /// 
-///     async fn terminate(&mut self) {
-/// 
+///     async fn terminate(&mut self) -> Result<(), ()> {
+///         Ok(())
 ///     }
 /// 
 /// 
@@ -119,23 +118,25 @@ use tokio::sync::oneshot::channel;
 /// 
 /// #[async_trait]
 /// impl Consumer<ProdEvent> for Log {
-///     async fn init(&mut self) {
+///     type Error = ();
 /// 
+///     async fn init(&mut self) -> Result<(), ()> {
+///         Ok(())
 ///     }
 /// 
-///     async fn handle_events(&mut self, events: Vec<ProdEvent>) -> State<ProdEvent> {
+///     async fn handle_events(&mut self, events: Vec<ProdEvent>) -> Result<State<ProdEvent>, ()> {
 ///         events
 ///             .into_iter()
 ///             .for_each(|pe| {
 ///                 println!("==> {} -> {}", pe.funame, pe.age)
 ///             });
 ///         
-///         State::Continue
+///         Ok(State::Continue)
 ///     }  
 /// 
 /// 
-///     async fn terminate(&mut self) {
-///         
+///     async fn terminate(&mut self) -> Result<(), ()> {
+///         Ok(())
 ///     }
 /// }
 /// 
@@ -143,16 +144,77 @@ use tokio::sync::oneshot::channel;
 /// ```
 pub use behaviors:: {
 
-    producer::Producer, producer::ProducerRunnable,
+    producer::Producer, producer::ProducerRunnable, producer::Emit,
    
     producer_consumer::ProducerConsumer, producer_consumer::ProducerConsumerRunnable,
    
     consumer::Consumer, consumer::ConsumerRunnable,
    
     consumer::State,
-    
+
+    subscription::Subscription,
+
+    shared::Shared,
+
+    shutdown::Shutdown,
+    shutdown::ShutdownToken,
+
+    stage::StageHandle,
+    stage::ExitReason,
+
+    error::StageError,
+    error::OnError,
+
+    idle::Idle,
+    idle::IdleWaker,
+
+    batcher::Batcher,
+    batcher::Batch,
+
+    window::Window,
+    window::Span,
+
+    supervisor::Supervisor,
+    supervisor::SupervisorHandle,
+    supervisor::Strategy,
+    supervisor::Link,
+
+    pipeline::Pipeline,
+    pipeline::PipelineHandle,
+
+    topology::Topology,
+    topology::StageInfo,
+    topology::StageKind,
+    topology::Edge,
+
+    metrics::Metrics,
+    metrics::Metric,
+    metrics::MemoryMetrics,
+    metrics::Snapshot,
+    metrics::StageMetrics,
+    metrics::Histogram,
+
+    message::Message,
+    message::Metadata,
+    message::Acknowledger,
+    message::Ack,
+    message::AckConsumer,
+
+    dead_letter::Failed,
+    dead_letter::DeadLetter,
+    dead_letter::DeadLetterTo,
+
+    retry::Retry,
+
+    dispatch::Dispatch,
+    dispatch::Subscribers,
+
+    router::Router,
+    router::Unmatched,
+
     DestinationDown,
     DispatcherType,
+    DispatcherHandle,
     Status
 
 
@@ -0,0 +1,119 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use last_stage::*;
+
+
+
+struct Numbers {
+    next: u64
+}
+
+#[async_trait]
+impl Producer<Shared<u64>> for Numbers {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    // batches bigger than demand of subscribers
+    async fn handle_demand(&mut self, _demand: usize) -> Result<Emit<Shared<u64>>, ()> {
+        let batch: Vec<u64> = (self.next..self.next + 100).collect();
+        self.next += 100;
+
+        if self.next == 1000 {
+            return Ok(Emit::Done(vec![Shared::from(batch)]))
+        }
+
+        Ok(Emit::Events(vec![Shared::from(batch)]))
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+#[derive(Default)]
+struct Received {
+    events  : Vec<u64>,
+    largest : usize,
+
+    // address of every received shared batch
+    batches : Vec<usize>
+}
+
+struct Collect(Arc<Mutex<Received>>);
+
+#[async_trait]
+impl Consumer<Shared<u64>> for Collect {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<Shared<u64>>) -> Result<State<Shared<u64>>, ()> {
+        let mut received = self.0.lock().unwrap();
+
+        let count: usize = events.iter().map(|shared| shared.len()).sum();
+        received.largest = received.largest.max(count);
+
+        for shared in events {
+            received.batches.push(shared.as_ptr() as usize);
+            received.events.extend(shared.iter());
+        }
+
+        Ok(State::Continue)
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+
+#[tokio::test]
+async fn every_subscriber_get_same_batches_within_demand() {
+    let mut shutdown = Shutdown::new();
+
+    let mut received = Vec::new();
+    let mut handles = Vec::new();
+    let mut chans = Vec::new();
+
+    for _ in 0..3 {
+        let state = Arc::new(Mutex::new(Received::default()));
+
+        let (chan, handle) = ConsumerRunnable::new(Box::new(Collect(state.clone())))
+            .with_demand(5, 10).unwrap()
+            .run(10);
+
+        received.push(state);
+        handles.push(handle);
+        chans.push(chan);
+    }
+
+    let (_producer, _) = ProducerRunnable::new(Box::new(Numbers { next: 0 }),
+                                               chans,
+                                               Some(DispatcherType::broadcast_shared()),
+                                               100,
+                                               shutdown.producer()).unwrap().run();
+
+    for handle in handles {
+        assert!(matches!(handle.await, ExitReason::UpstreamClosed));
+    }
+
+    let first = received[0].lock().unwrap().batches.clone();
+
+    for state in received.iter() {
+        let state = state.lock().unwrap();
+
+        assert_eq!(state.events, (0..1000).collect::<Vec<u64>>());
+        assert!(state.largest <= 10, "sent {} events over demand", state.largest);
+
+        // fan-out shared batches, not copies of them
+        assert_eq!(state.batches, first);
+    }
+}
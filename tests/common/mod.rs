@@ -0,0 +1,218 @@
+// shared by every test crate, each one use only some of it
+#![allow(dead_code)]
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use last_stage::*;
+
+
+
+/// emit 0, 1, 2, .. by demand, Done when reach `until`
+pub struct Numbers {
+    pub next    : u64,
+    pub until   : Option<u64>,
+
+    // most events emitted by a handle_demand
+    pub batch   : usize,
+
+    // sleep before every handle_demand
+    pub delay   : Duration,
+
+    pub inits   : Arc<AtomicUsize>,
+    pub emitted : Arc<AtomicUsize>
+}
+
+
+impl Numbers {
+
+    pub fn endless() -> Self {
+        Numbers {
+            next: 0,
+            until: None,
+            batch: usize::MAX,
+            delay: Duration::ZERO,
+            inits: Arc::new(AtomicUsize::new(0)),
+            emitted: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    pub fn until(until: u64) -> Self {
+        Numbers {
+            until: Some(until),
+            ..Numbers::endless()
+        }
+    }
+
+    pub fn batch(mut self, batch: usize) -> Self {
+        self.batch = batch;
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn inits(mut self, inits: Arc<AtomicUsize>) -> Self {
+        self.inits = inits;
+        self
+    }
+}
+
+
+#[async_trait]
+impl Producer<u64> for Numbers {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        self.inits.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn handle_demand(&mut self, demand: usize) -> Result<Emit<u64>, ()> {
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+
+        let mut end = self.next + demand.min(self.batch) as u64;
+        if let Some(until) = self.until {
+            end = end.min(until);
+        }
+
+        let events: Vec<u64> = (self.next..end).collect();
+        self.next = end;
+        self.emitted.fetch_add(events.len(), Ordering::SeqCst);
+
+        if Some(self.next) == self.until {
+            return Ok(Emit::Done(events))
+        }
+
+        Ok(Emit::Events(events))
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+
+/// count inits and received events, sleep `delay`
+/// after every batch, set `terminated` in terminate
+#[derive(Clone)]
+pub struct Count {
+    pub inits      : Arc<AtomicUsize>,
+    pub events     : Arc<AtomicUsize>,
+    pub terminated : Arc<AtomicBool>,
+    pub delay      : Duration
+}
+
+
+impl Count {
+
+    pub fn new() -> Self {
+        Count {
+            inits: Arc::new(AtomicUsize::new(0)),
+            events: Arc::new(AtomicUsize::new(0)),
+            terminated: Arc::new(AtomicBool::new(false)),
+            delay: Duration::ZERO
+        }
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+
+#[async_trait]
+impl<T: Send + 'static> Consumer<T> for Count {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        self.inits.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<T>) -> Result<State<T>, ()> {
+        self.events.fetch_add(events.len(), Ordering::SeqCst);
+
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+
+        Ok(State::Continue)
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        self.terminated.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+
+
+/// record received events, terminate after
+/// `stop_after` events, sleep `delay` before every batch
+pub struct Record<T> {
+    pub received   : Arc<Mutex<Vec<T>>>,
+    pub stop_after : Option<usize>,
+    pub delay      : Duration
+}
+
+
+impl<T> Record<T> {
+
+    pub fn new() -> Self {
+        Record {
+            received: Arc::new(Mutex::new(Vec::new())),
+            stop_after: None,
+            delay: Duration::ZERO
+        }
+    }
+}
+
+
+#[async_trait]
+impl<T: Send + 'static> Consumer<T> for Record<T> {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<T>) -> Result<State<T>, ()> {
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+
+        let mut received = self.received.lock().unwrap();
+        received.extend(events);
+
+        match self.stop_after {
+            Some(stop_after) if received.len() >= stop_after => Ok(State::Terminate),
+            _ => Ok(State::Continue)
+        }
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+
+/// wait until done return true, panic after a second
+pub async fn wait_until(done: impl Fn() -> bool) {
+    let wait = async {
+        while !done() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(1), wait).await.expect("not done in a second");
+}
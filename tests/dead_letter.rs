@@ -0,0 +1,89 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use last_stage::*;
+
+mod common;
+use common::Numbers;
+
+
+
+/// fail every event
+struct FailAll {
+    failed: Vec<Failed<u64>>
+}
+
+#[async_trait]
+impl Consumer<u64> for FailAll {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<u64>) -> Result<State<u64>, ()> {
+        self.failed.extend(events.into_iter().map(|event| Failed::fatal(event, "fail")));
+        Ok(State::Continue)
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn take_failed(&mut self) -> Vec<Failed<u64>> {
+        std::mem::take(&mut self.failed)
+    }
+}
+
+
+/// record size of every received batch
+struct Dead(Arc<Mutex<Vec<usize>>>);
+
+#[async_trait]
+impl Consumer<DeadLetter<u64>> for Dead {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, letters: Vec<DeadLetter<u64>>) -> Result<State<DeadLetter<u64>>, ()> {
+        self.0.lock().unwrap().push(letters.len());
+        Ok(State::Continue)
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+
+#[tokio::test]
+async fn dead_letter_stage_get_events_by_its_demand() {
+    let mut shutdown = Shutdown::new();
+    let batches = Arc::new(Mutex::new(Vec::new()));
+
+    let (dead_chan, dead) = ConsumerRunnable::new(Box::new(Dead(batches.clone())))
+        .with_demand(1, 2).unwrap()
+        .run(10);
+
+    let (fail_chan, fail) = ConsumerRunnable::new(Box::new(FailAll { failed: Vec::new() }))
+        .with_demand(10, 20).unwrap()
+        .with_dead_letter(dead_chan)
+        .run(10);
+
+    let (_producer, _) = ProducerRunnable::new(Box::new(Numbers::until(100)),
+                                               vec![fail_chan],
+                                               None,
+                                               100,
+                                               shutdown.producer()).unwrap().run();
+
+    assert!(matches!(fail.await, ExitReason::UpstreamClosed));
+    assert!(matches!(dead.await, ExitReason::UpstreamClosed));
+
+    let batches = batches.lock().unwrap();
+
+    assert_eq!(batches.iter().sum::<usize>(), 100);
+    assert!(batches.iter().all(|len| *len <= 2), "sent over demand {:?}", batches);
+}
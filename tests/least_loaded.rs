@@ -0,0 +1,34 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use last_stage::*;
+
+mod common;
+use common::{wait_until, Count, Numbers};
+
+
+
+#[tokio::test]
+async fn slow_subscriber_not_stall_fast_one() {
+    let slow = Count::new().delay(Duration::from_secs(3));
+    let fast = Count::new().delay(Duration::from_millis(20));
+    let received = fast.events.clone();
+
+    let (slow_chan, _) = ConsumerRunnable::new(Box::new(slow))
+        .with_demand(50, 100).unwrap()
+        .run(1);
+
+    let (fast_chan, _) = ConsumerRunnable::new(Box::new(fast))
+        .with_demand(5, 10).unwrap()
+        .run(1);
+
+    // small batches, slow subscriber keep demand while its channel full
+    let (_producer, _) = ProducerRunnable::new(Box::new(Numbers::endless().batch(10)),
+                                               vec![slow_chan, fast_chan],
+                                               Some(DispatcherType::LeastLoaded),
+                                               100,
+                                               Shutdown::new().producer()).unwrap().run();
+
+    // fast subscriber handle a batch every 20ms while slow one sleep
+    wait_until(|| received.load(Ordering::SeqCst) > 100).await;
+}
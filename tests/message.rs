@@ -0,0 +1,109 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use last_stage::*;
+
+
+
+#[derive(Default)]
+struct Settled {
+    acked  : Vec<String>,
+    nacked : Vec<String>
+}
+
+struct Queue(Arc<Mutex<Settled>>);
+
+impl Acknowledger for Queue {
+    fn ack(&self, metadata: &Metadata) {
+        self.0.lock().unwrap().acked.push(metadata.id.clone());
+    }
+
+    fn nack(&self, metadata: &Metadata, _reason: &str) {
+        self.0.lock().unwrap().nacked.push(metadata.id.clone());
+    }
+}
+
+
+struct Messages {
+    queue: Arc<Queue>
+}
+
+#[async_trait]
+impl Producer<Message<u64>> for Messages {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_demand(&mut self, _demand: usize) -> Result<Emit<Message<u64>>, ()> {
+        Ok(Emit::Done((0..4)
+            .map(|id| Message::new(id, Metadata::new(id.to_string()), self.queue.clone()))
+            .collect()))
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+/// fail odd events
+struct FailOdd {
+    failed: Vec<Failed<u64>>
+}
+
+#[async_trait]
+impl Consumer<u64> for FailOdd {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<u64>) -> Result<State<u64>, ()> {
+        self.failed.extend(events.into_iter().filter(|id| id % 2 == 1).map(|id| Failed::fatal(id, "odd")));
+        Ok(State::Continue)
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn take_failed(&mut self) -> Vec<Failed<u64>> {
+        std::mem::take(&mut self.failed)
+    }
+}
+
+
+
+#[tokio::test]
+async fn failed_events_nacked_and_dead_lettered() {
+    let mut shutdown = Shutdown::new();
+    let settled = Arc::new(Mutex::new(Settled::default()));
+
+    let (dead_sx, mut dead_rx) = tokio::sync::mpsc::channel(10);
+
+    let (sink_chan, sink) = ConsumerRunnable::new(Box::new(AckConsumer::new(FailOdd { failed: Vec::new() })))
+        .with_dead_letter(dead_sx)
+        .run(10);
+
+    let (_producer, _) = ProducerRunnable::new(Box::new(Messages { queue: Arc::new(Queue(settled.clone())) }),
+                                               vec![sink_chan],
+                                               None,
+                                               100,
+                                               shutdown.producer()).unwrap().run();
+
+    assert!(matches!(sink.await, ExitReason::UpstreamClosed));
+
+    let mut dead = Vec::new();
+    while let Some(letters) = dead_rx.recv().await {
+        dead.extend(letters.into_iter().map(|letter: DeadLetter<Message<u64>>| letter.event.metadata().id.clone()));
+    }
+
+    let settled = settled.lock().unwrap();
+
+    assert_eq!(settled.acked, ["0", "2"]);
+    assert_eq!(settled.nacked, ["1", "3"]);
+    assert_eq!(dead, ["1", "3"]);
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use last_stage::*;
+use tokio::sync::Notify;
+
+mod common;
+use common::Record;
+
+
+
+const KEYS: u64 = 32;
+const EVENTS: u64 = 4000;
+
+
+/// producer wait for `resume` after half of events dispatched
+struct Pause {
+    paused : Arc<Notify>,
+    resume : Arc<Notify>
+}
+
+
+/// emit (key, seq) events, seq increase for every key
+struct Keyed {
+    next  : u64,
+    pause : Option<Pause>
+}
+
+#[async_trait]
+impl Producer<(u64, u64)> for Keyed {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_demand(&mut self, demand: usize) -> Result<Emit<(u64, u64)>, ()> {
+        let mut end = EVENTS;
+
+        if let Some(pause) = &self.pause {
+            if self.next == EVENTS / 2 {
+                pause.paused.notify_one();
+                pause.resume.notified().await;
+            }
+
+            if self.next < EVENTS / 2 {
+                end = EVENTS / 2;
+            }
+        }
+
+        let end = (self.next + demand as u64).min(end);
+        let events = (self.next..end).map(|i| (i % KEYS, i / KEYS)).collect();
+        self.next = end;
+
+        if self.next == EVENTS {
+            return Ok(Emit::Done(events))
+        }
+
+        Ok(Emit::Events(events))
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+type Received = Arc<Mutex<Vec<(u64, u64)>>>;
+
+
+/// running recording consumers
+struct Running {
+    chans    : Vec<Subscription<(u64, u64)>>,
+    handles  : Vec<StageHandle<(u64, u64), ()>>,
+    received : Vec<Received>
+}
+
+
+fn run(records: Vec<Record<(u64, u64)>>) -> Running {
+    let mut chans = Vec::new();
+    let mut handles = Vec::new();
+    let mut received = Vec::new();
+
+    for record in records {
+        received.push(record.received.clone());
+
+        let (chan, handle) = ConsumerRunnable::new(Box::new(record))
+            .with_demand(5, 10).unwrap()
+            .run(1);
+
+        chans.push(chan);
+        handles.push(handle);
+    }
+
+    Running { chans, handles, received }
+}
+
+
+/// run keyed producer by Partition dispatcher
+fn partition(chans: Vec<Subscription<(u64, u64)>>, pause: Option<Pause>) -> DispatcherHandle<(u64, u64)> {
+    let (_producer, dispatcher) = ProducerRunnable::new(Box::new(Keyed { next: 0, pause }),
+                                                        chans,
+                                                        Some(DispatcherType::partition(|event: &(u64, u64)| event.0)),
+                                                        100,
+                                                        Shutdown::new().producer()).unwrap().run();
+    dispatcher
+}
+
+
+/// events of every subscriber, checked nothing lost or sent twice
+fn collect(received: Vec<Received>) -> Vec<Vec<(u64, u64)>> {
+    let received: Vec<Vec<(u64, u64)>> = received.iter().map(|r| r.lock().unwrap().clone()).collect();
+
+    let mut all: Vec<(u64, u64)> = received.concat();
+    all.sort();
+
+    let mut expected: Vec<(u64, u64)> = (0..EVENTS).map(|i| (i % KEYS, i / KEYS)).collect();
+    expected.sort();
+
+    assert_eq!(all, expected);
+
+    received
+}
+
+
+/// subscriber index of every key, None if key went to many subscribers
+fn owners(received: &[Vec<(u64, u64)>]) -> HashMap<u64, Option<usize>> {
+    let mut owners = HashMap::new();
+
+    for (index, events) in received.iter().enumerate() {
+        for (key, _) in events {
+            owners.entry(*key)
+                .and_modify(|owner: &mut Option<usize>| if *owner != Some(index) { *owner = None })
+                .or_insert(Some(index));
+        }
+    }
+
+    owners
+}
+
+
+/// seq of every key increase in events of a subscriber
+fn ordered(events: &[(u64, u64)]) -> bool {
+    let mut last: HashMap<u64, u64> = HashMap::new();
+
+    events.iter().all(|(key, seq)| {
+        let ordered = last.get(key).is_none_or(|last| seq > last);
+        last.insert(*key, *seq);
+        ordered
+    })
+}
+
+
+/// keys of removed subscriber moved to others, every moved
+/// key to only one of them, keys of others never moved
+fn remapped(received: &[Vec<(u64, u64)>], removed: usize) {
+    let mut others = received.to_vec();
+    others[removed].clear();
+
+    let before = owners(received);
+    let after = owners(&others);
+
+    for (key, owner) in before.iter() {
+        let moved = received[removed].iter().any(|(k, _)| k == key);
+
+        assert_eq!(owner.is_some(), !moved, "key {}", key);
+        assert!(after[key].is_some(), "key {} moved to many subscribers", key);
+    }
+}
+
+
+
+#[tokio::test]
+async fn events_of_key_go_to_one_subscriber_in_order() {
+    let mut records: Vec<Record<(u64, u64)>> = (0..4).map(|_| Record::new()).collect();
+    records[0].delay = Duration::from_millis(1);
+
+    let Running { chans, handles, received } = run(records);
+
+    partition(chans, None);
+
+    for handle in handles {
+        handle.await;
+    }
+
+    let received = collect(received);
+
+    for (key, owner) in owners(&received) {
+        assert!(owner.is_some(), "key {} went to many subscribers", key);
+    }
+
+    // every subscriber got some keys
+    assert!(received.iter().all(|events| !events.is_empty()));
+
+    for events in received.iter() {
+        assert!(ordered(events));
+    }
+}
+
+
+#[tokio::test]
+async fn keys_of_stopped_subscriber_move_others_stay() {
+    let mut records: Vec<Record<(u64, u64)>> = (0..3).map(|_| Record::new()).collect();
+    records[1].stop_after = Some(200);
+
+    let Running { chans, handles, received } = run(records);
+
+    partition(chans, None);
+
+    for handle in handles {
+        handle.await;
+    }
+
+    let received = collect(received);
+
+    remapped(&received, 1);
+
+    for events in received.iter() {
+        assert!(ordered(events));
+    }
+}
+
+
+#[tokio::test]
+async fn keys_of_other_subscribers_stay_when_one_unsubscribed() {
+    let Running { chans, handles, received } = run((0..3).map(|_| Record::new()).collect());
+
+    let removed = chans[1].clone();
+
+    let paused = Arc::new(Notify::new());
+    let resume = Arc::new(Notify::new());
+
+    let dispatcher = partition(chans, Some(Pause { paused: paused.clone(), resume: resume.clone() }));
+
+    // half of events dispatched, remove second subscriber,
+    // request applied before next dispatch of resumed producer
+    paused.notified().await;
+
+    let (res, _) = tokio::join!(dispatcher.unsubscribe(removed), async { resume.notify_one() });
+    res.unwrap();
+
+    for handle in handles {
+        handle.await;
+    }
+
+    let received = collect(received);
+
+    // removed subscriber got only events before unsubscribe
+    assert!(received[1].iter().all(|(key, seq)| seq * KEYS + key < EVENTS / 2));
+
+    remapped(&received, 1);
+
+    for events in received.iter() {
+        assert!(ordered(events));
+    }
+}
@@ -0,0 +1,176 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use last_stage::*;
+use tokio::sync::mpsc;
+
+
+
+/// emit one batch, then Done
+struct Once {
+    batch: Vec<u32>
+}
+
+#[async_trait]
+impl Producer<u32> for Once {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_demand(&mut self, _demand: usize) -> Result<Emit<u32>, ()> {
+        Ok(Emit::Done(std::mem::take(&mut self.batch)))
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+/// result of a call by its number and batch,
+/// Ok with per-event failures or Err
+type Script = fn(usize, &[u32]) -> Result<Vec<Failed<u32>>, &'static str>;
+
+
+/// fail calls by script, record every batch it got
+struct Flaky {
+    calls  : Arc<Mutex<Vec<Vec<u32>>>>,
+    script : Script,
+    failed : Vec<Failed<u32>>
+}
+
+#[async_trait]
+impl Consumer<u32> for Flaky {
+    type Error = &'static str;
+
+    async fn init(&mut self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<u32>) -> Result<State<u32>, &'static str> {
+        let call = {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(events.clone());
+            calls.len()
+        };
+
+        self.failed = (self.script)(call, &events)?;
+        Ok(State::Continue)
+    }
+
+    async fn terminate(&mut self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn take_failed(&mut self) -> Vec<Failed<u32>> {
+        std::mem::take(&mut self.failed)
+    }
+}
+
+
+/// run [1..=6] through Flaky with retry policy, return
+/// its exit reason, batches of every call and dead letters
+async fn run(retry: Retry<&'static str>, script: Script) -> (ExitReason<u32, &'static str>, Vec<Vec<u32>>, Vec<DeadLetter<u32>>) {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let (dead_sx, mut dead_rx) = mpsc::channel(10);
+
+    let (chan, handle) = ConsumerRunnable::new(Box::new(Flaky { calls: calls.clone(), script, failed: Vec::new() }))
+        .with_retry(retry.backoff(Duration::from_millis(1), Duration::from_millis(10)))
+        .with_dead_letter(dead_sx)
+        .run(10);
+
+    ProducerRunnable::new(Box::new(Once { batch: (1..=6).collect() }), vec![chan], None, 10, Shutdown::new().producer())
+        .unwrap()
+        .run();
+
+    let reason = handle.await;
+
+    let mut dead = Vec::new();
+    while let Ok(letters) = dead_rx.try_recv() {
+        dead.extend(letters);
+    }
+
+    let calls = calls.lock().unwrap().clone();
+    (reason, calls, dead)
+}
+
+
+fn odd(events: &[u32]) -> Vec<u32> {
+    events.iter().copied().filter(|event| event % 2 == 1).collect()
+}
+
+
+
+#[tokio::test(start_paused = true)]
+async fn whole_batch_retried_after_retryable_error() {
+    let (reason, calls, dead) = run(Retry::new(5), |call, _| {
+        if call < 3 { Err("busy") } else { Ok(Vec::new()) }
+    }).await;
+
+    assert!(matches!(reason, ExitReason::UpstreamClosed));
+    assert_eq!(calls, vec![vec![1, 2, 3, 4, 5, 6]; 3]);
+    assert!(dead.is_empty());
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn only_failed_subset_retried() {
+    let (reason, calls, dead) = run(Retry::new(5), |call, events| {
+        match call {
+            1 => Ok(odd(events).into_iter().map(|event| Failed::new(event, "busy")).collect()),
+            _ => Ok(Vec::new())
+        }
+    }).await;
+
+    assert!(matches!(reason, ExitReason::UpstreamClosed));
+    assert_eq!(calls, vec![vec![1, 2, 3, 4, 5, 6], vec![1, 3, 5]]);
+    assert!(dead.is_empty());
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn error_not_retryable_is_fatal() {
+    let retry = Retry::new(5).retryable(|err: &&'static str| *err != "corrupt");
+
+    let (reason, calls, _) = run(retry, |_, _| Err("corrupt")).await;
+
+    assert!(matches!(reason, ExitReason::Failed(StageError::Handle("corrupt"))));
+    assert_eq!(calls.len(), 1);
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn fatal_events_go_to_dead_letter_without_retry() {
+    let (_, calls, dead) = run(Retry::new(5), |_, events| {
+        Ok(events.iter().filter(|event| **event == 2).map(|event| Failed::fatal(*event, "invalid")).collect())
+    }).await;
+
+    assert_eq!(calls.len(), 1);
+    assert_eq!(dead.iter().map(|letter| letter.event).collect::<Vec<_>>(), vec![2]);
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn exhausted_error_go_to_on_error() {
+    let (reason, calls, _) = run(Retry::new(3), |_, _| Err("busy")).await;
+
+    assert!(matches!(reason, ExitReason::Failed(StageError::Handle("busy"))));
+    assert_eq!(calls.len(), 3);
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn exhausted_events_go_to_dead_letter() {
+    let (reason, calls, dead) = run(Retry::new(3), |_, events| {
+        Ok(odd(events).into_iter().map(|event| Failed::new(event, "busy")).collect())
+    }).await;
+
+    assert!(matches!(reason, ExitReason::UpstreamClosed));
+    assert_eq!(calls, vec![vec![1, 2, 3, 4, 5, 6], vec![1, 3, 5], vec![1, 3, 5]]);
+
+    let dead: Vec<(u32, String)> = dead.into_iter().map(|letter| (letter.event, letter.reason)).collect();
+    assert_eq!(dead, vec![(1, "busy".to_string()), (3, "busy".to_string()), (5, "busy".to_string())]);
+}
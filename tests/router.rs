@@ -0,0 +1,64 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use last_stage::*;
+
+mod common;
+use common::{wait_until, Numbers, Record};
+
+
+
+fn record() -> (Arc<Mutex<Vec<u64>>>, Subscription<u64>) {
+    let record = Record::new();
+    let received = record.received.clone();
+
+    let (sub, _) = ConsumerRunnable::new(Box::new(record)).run(10);
+    (received, sub)
+}
+
+
+fn even(event: &u64) -> bool {
+    event.is_multiple_of(2)
+}
+
+
+fn received(record: &Arc<Mutex<Vec<u64>>>) -> Vec<u64> {
+    record.lock().unwrap().clone()
+}
+
+
+
+#[tokio::test]
+async fn route_kept_by_subscription_subscribed_again() {
+    let (evens, evens_chan) = record();
+    let (rest, rest_chan) = record();
+
+    let evens_chan = evens_chan.with_selector(even);
+
+    let numbers = Numbers::endless().batch(10).delay(Duration::from_millis(5));
+
+    let (_producer, handle) = ProducerRunnable::new(Box::new(numbers),
+                                                    vec![evens_chan.clone(), rest_chan],
+                                                    Some(DispatcherType::custom(Router::new())),
+                                                    100,
+                                                    Shutdown::new().producer()).unwrap().run();
+
+    wait_until(|| !received(&evens).is_empty()).await;
+
+    // replace evens subscriber with a new one, new id
+    let (again, again_chan) = record();
+    handle.subscribe(again_chan.with_selector(even)).await.unwrap();
+    handle.unsubscribe(evens_chan).await.unwrap();
+
+    wait_until(|| !received(&again).is_empty()).await;
+
+    let evens = received(&evens);
+    let again = received(&again);
+    let rest = received(&rest);
+
+    assert!(evens.iter().chain(again.iter()).all(even));
+
+    // unmatched go to subscriber without selector
+    assert!(!rest.is_empty());
+    assert!(rest.iter().all(|event| !even(event)));
+}
@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use last_stage::*;
+
+mod common;
+use common::{wait_until, Count, Numbers};
+
+
+
+/// pass events after delay, set flag in terminate
+struct Slow {
+    delay      : Duration,
+    terminated : Arc<AtomicBool>
+}
+
+#[async_trait]
+impl ProducerConsumer<u64, u64> for Slow {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<u64>) -> Result<Vec<u64>, ()> {
+        tokio::time::sleep(self.delay).await;
+        Ok(events)
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        self.terminated.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+
+
+#[tokio::test(start_paused = true)]
+async fn shutdown_drain_in_flight_events_and_wait_terminate() {
+    let mut shutdown = Shutdown::new();
+
+    let slow_terminated = Arc::new(AtomicBool::new(false));
+
+    let count = Count::new().delay(Duration::from_millis(5));
+    let consumed = count.events.clone();
+    let count_terminated = count.terminated.clone();
+
+    let numbers = Numbers::endless();
+    let emitted = numbers.emitted.clone();
+
+    let (count_chan, count) = ConsumerRunnable::new(Box::new(count))
+        .with_shutdown(&shutdown)
+        .run(10);
+
+    let (slow_chan, slow, _) = ProducerConsumerRunnable::new(Box::new(Slow { delay: Duration::from_millis(10), terminated: slow_terminated.clone() }),
+                                                             vec![count_chan],
+                                                             None).unwrap()
+        .with_shutdown(&shutdown)
+        .run(10);
+
+    let (producer, _) = ProducerRunnable::new(Box::new(numbers),
+                                              vec![slow_chan],
+                                              None,
+                                              10,
+                                              shutdown.producer()).unwrap()
+        .with_shutdown(&shutdown)
+        .run();
+
+    // events flow, batches in channels of both stages
+    wait_until(|| consumed.load(Ordering::SeqCst) > 0).await;
+
+    shutdown.shutdown(Duration::from_secs(5)).await.unwrap();
+
+    // resolved only after every stage terminated
+    assert!(slow_terminated.load(Ordering::SeqCst));
+    assert!(count_terminated.load(Ordering::SeqCst));
+
+    assert!(consumed.load(Ordering::SeqCst) > 0);
+    assert_eq!(consumed.load(Ordering::SeqCst), emitted.load(Ordering::SeqCst));
+
+    assert!(matches!(producer.await, ExitReason::Normal));
+    assert!(matches!(slow.await, ExitReason::UpstreamClosed));
+    assert!(matches!(count.await, ExitReason::UpstreamClosed));
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn stages_not_stopped_before_deadline_aborted() {
+    let mut shutdown = Shutdown::new();
+
+    let count = Count::new().delay(Duration::from_secs(3600));
+    let consumed = count.events.clone();
+    let terminated = count.terminated.clone();
+
+    let (count_chan, count) = ConsumerRunnable::new(Box::new(count))
+        .with_shutdown(&shutdown)
+        .run(10);
+
+    let (producer, _) = ProducerRunnable::new(Box::new(Numbers::endless()),
+                                              vec![count_chan],
+                                              None,
+                                              10,
+                                              shutdown.producer()).unwrap()
+        .with_shutdown(&shutdown)
+        .run();
+
+    // consumer stuck in handle_events
+    wait_until(|| consumed.load(Ordering::SeqCst) > 0).await;
+
+    let res = shutdown.shutdown(Duration::from_millis(100)).await;
+
+    assert!(matches!(res, Err(Status::DeadlineElapsed)));
+    assert!(count.is_finished());
+    assert!(!terminated.load(Ordering::SeqCst));
+
+    // producer stuck on full channel of consumer
+    assert!(matches!(producer.await, ExitReason::Aborted));
+    assert!(matches!(count.await, ExitReason::Aborted));
+}
@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use last_stage::*;
+
+mod common;
+use common::{wait_until, Count, Numbers};
+
+
+
+struct PanicOnce {
+    inits  : Arc<AtomicUsize>,
+    events : Arc<AtomicUsize>
+}
+
+#[async_trait]
+impl Consumer<u64> for PanicOnce {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        self.inits.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<u64>) -> Result<State<u64>, ()> {
+        if self.inits.load(Ordering::SeqCst) == 1 {
+            panic!("first instance panic");
+        }
+
+        self.events.fetch_add(events.len(), Ordering::SeqCst);
+        Ok(State::Continue)
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+struct PanicOnceMiddle {
+    inits : Arc<AtomicUsize>
+}
+
+#[async_trait]
+impl ProducerConsumer<u64, u64> for PanicOnceMiddle {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        self.inits.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<u64>) -> Result<Vec<u64>, ()> {
+        if self.inits.load(Ordering::SeqCst) == 1 {
+            panic!("first instance panic");
+        }
+
+        Ok(events)
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+/// producer -> panic once producer_consumer -> consumer,
+/// return inits of producer, producer_consumer and consumer
+async fn restart_middle(strategy: Strategy) -> [usize; 3] {
+    let inits: [Arc<AtomicUsize>; 3] = Default::default();
+
+    let mut sup = Supervisor::new(strategy);
+
+    let count = Count { inits: inits[2].clone(), ..Count::new() };
+    let events = count.events.clone();
+    let log = sup.consumer(10, move || {
+        ConsumerRunnable::new(Box::new(count.clone()))
+    });
+
+    let middle_inits = inits[1].clone();
+    let middle = sup.producer_consumer(10, vec![log], move |subs| {
+        ProducerConsumerRunnable::new(Box::new(PanicOnceMiddle { inits: middle_inits.clone() }), subs, None)
+    });
+
+    let producer_inits = inits[0].clone();
+    sup.producer(vec![middle], move |subs, shutdown| {
+        ProducerRunnable::new(Box::new(Numbers::endless().inits(producer_inits.clone())), subs, None, 100, shutdown)
+    });
+
+    let handle = sup.run();
+
+    // restarted producer_consumer send events to running consumer
+    wait_until(|| inits[1].load(Ordering::SeqCst) == 2).await;
+    wait_until(|| events.load(Ordering::SeqCst) > 0).await;
+
+    handle.shutdown(Duration::from_secs(1)).await.unwrap();
+
+    inits.map(|inits| inits.load(Ordering::SeqCst))
+}
+
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn panicked_producer_consumer_restarted_one_for_one() {
+    assert_eq!(restart_middle(Strategy::OneForOne).await, [1, 2, 1]);
+}
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn panicked_producer_consumer_restarted_rest_for_one() {
+    assert_eq!(restart_middle(Strategy::RestForOne).await, [2, 2, 1]);
+}
+
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upstream_of_only_restarted_consumer_not_restarted() {
+    let producer_inits = Arc::new(AtomicUsize::new(0));
+    let consumer_inits = Arc::new(AtomicUsize::new(0));
+    let events = Arc::new(AtomicUsize::new(0));
+
+    let mut sup = Supervisor::new(Strategy::OneForOne);
+
+    let (inits, consumed) = (consumer_inits.clone(), events.clone());
+    let log = sup.consumer(10, move || {
+        ConsumerRunnable::new(Box::new(PanicOnce { inits: inits.clone(), events: consumed.clone() }))
+    });
+
+    let inits = producer_inits.clone();
+    sup.producer(vec![log], move |subs, shutdown| {
+        ProducerRunnable::new(Box::new(Numbers::endless().inits(inits.clone())), subs, None, 100, shutdown)
+    });
+
+    let handle = sup.run();
+
+    // restarted consumer handled events
+    wait_until(|| events.load(Ordering::SeqCst) > 0).await;
+
+    assert_eq!(consumer_inits.load(Ordering::SeqCst), 2);
+    assert_eq!(producer_inits.load(Ordering::SeqCst), 1);
+    assert!(events.load(Ordering::SeqCst) > 0);
+
+    handle.shutdown(Duration::from_secs(1)).await.unwrap();
+}
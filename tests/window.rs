@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use last_stage::*;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+
+
+
+/// (key, event time)
+type Event = (u32, Duration);
+
+/// (key, span, event times of window)
+type Closed = (u32, Span, Vec<Duration>);
+
+
+/// emit batches sent by test, Done when sender dropped
+struct Feed {
+    rx: UnboundedReceiver<Vec<Event>>
+}
+
+#[async_trait]
+impl Producer<Event> for Feed {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_demand(&mut self, _demand: usize) -> Result<Emit<Event>, ()> {
+        match self.rx.recv().await {
+            Some(events) => Ok(Emit::Events(events)),
+            None => Ok(Emit::Done(Vec::new()))
+        }
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+/// send closed windows to test
+struct Collect {
+    sx: UnboundedSender<Closed>
+}
+
+#[async_trait]
+impl Consumer<Closed> for Collect {
+    type Error = ();
+
+    async fn init(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    async fn handle_events(&mut self, events: Vec<Closed>) -> Result<State<Closed>, ()> {
+        for closed in events {
+            let _ = self.sx.send(closed);
+        }
+
+        Ok(State::Continue)
+    }
+
+    async fn terminate(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+
+fn times(_key: &u32, span: Span, events: Vec<Event>) -> Closed {
+    (events[0].0, span, events.into_iter().map(|(_, ts)| ts).collect())
+}
+
+
+fn key(event: &Event) -> u32 {
+    event.0
+}
+
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+
+fn span(start: u64, end: u64) -> Span {
+    Span { start: ms(start), end: ms(end) }
+}
+
+
+/// run feed -> window -> collect, return sender of
+/// events, receiver of closed windows and consumer handle
+fn run(window: Window<Event, u32, Closed>) -> (UnboundedSender<Vec<Event>>, UnboundedReceiver<Closed>, StageHandle<Closed, ()>) {
+    let (events, rx) = mpsc::unbounded_channel();
+    let (sx, closed) = mpsc::unbounded_channel();
+
+    let (collect_chan, collect) = ConsumerRunnable::new(Box::new(Collect { sx })).run(10);
+
+    let (window_chan, _, _) = ProducerConsumerRunnable::new(Box::new(window), vec![collect_chan], None)
+        .unwrap()
+        .run(10);
+
+    ProducerRunnable::new(Box::new(Feed { rx }), vec![window_chan], None, 10, Shutdown::new().producer())
+        .unwrap()
+        .run();
+
+    (events, closed, collect)
+}
+
+
+
+#[tokio::test(start_paused = true)]
+async fn sliding_event_go_to_every_window_it_belong() {
+    let window = Window::sliding(ms(3000), ms(1000), key, times).event_time(|event: &Event| event.1, Duration::ZERO);
+    let (events, mut closed, _) = run(window);
+
+    events.send(vec![(1, ms(10_500))]).unwrap();
+
+    let mut spans = Vec::new();
+    for _ in 0..3 {
+        let (_, span, times) = closed.recv().await.unwrap();
+
+        assert_eq!(times, vec![ms(10_500)]);
+        spans.push(span);
+    }
+
+    assert_eq!(spans, vec![span(8000, 11_000), span(9000, 12_000), span(10_000, 13_000)]);
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn sessions_touched_by_event_merged() {
+    let window = Window::session(ms(1000), key, times).event_time(|event: &Event| event.1, ms(1000));
+    let (events, mut closed, _) = run(window);
+
+    // 1800 touch both sessions, 5000 start a new one
+    events.send(vec![(1, ms(1000)), (1, ms(2500)), (1, ms(1800)), (1, ms(5000))]).unwrap();
+
+    let (_, first, mut times) = closed.recv().await.unwrap();
+    times.sort();
+
+    assert_eq!(first, span(1000, 3500));
+    assert_eq!(times, vec![ms(1000), ms(1800), ms(2500)]);
+
+    let (_, second, times) = closed.recv().await.unwrap();
+
+    assert_eq!(second, span(5000, 6000));
+    assert_eq!(times, vec![ms(5000)]);
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn out_of_order_events_kept_late_events_dropped() {
+    let window = Window::tumbling(ms(1000), key, times).event_time(|event: &Event| event.1, ms(500));
+    let (events, mut closed, _) = run(window);
+
+    // 4900, 5800 out of order within lateness, 6100 move
+    // watermark to 5600, then 4200 is late for window 4000-5000
+    events.send(vec![(1, ms(5200)), (1, ms(4900)), (1, ms(6100)), (1, ms(4200)), (1, ms(5800))]).unwrap();
+
+    assert_eq!(closed.recv().await.unwrap(), (1, span(4000, 5000), vec![ms(4900)]));
+    assert_eq!(closed.recv().await.unwrap(), (1, span(5000, 6000), vec![ms(5200), ms(5800)]));
+    assert_eq!(closed.recv().await.unwrap(), (1, span(6000, 7000), vec![ms(6100)]));
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn watermark_move_with_clock_without_events() {
+    let window = Window::tumbling(ms(1000), key, times).event_time(|event: &Event| event.1, Duration::ZERO);
+    let (events, mut closed, _) = run(window);
+
+    let start = Instant::now();
+    events.send(vec![(1, ms(100_200))]).unwrap();
+
+    // watermark 100200, window end 101000
+    assert_eq!(closed.recv().await.unwrap(), (1, span(100_000, 101_000), vec![ms(100_200)]));
+    assert_eq!(start.elapsed(), ms(800));
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn open_windows_closed_when_upstream_closed() {
+    let window = Window::tumbling(Duration::from_secs(60), key, times);
+    let (events, mut closed, collect) = run(window);
+
+    let start = Instant::now();
+    events.send(vec![(1, Duration::ZERO), (2, Duration::ZERO), (1, Duration::ZERO)]).unwrap();
+    drop(events);
+
+    let mut keys = Vec::new();
+    while let Some((key, span, times)) = closed.recv().await {
+        assert_eq!(span.end - span.start, Duration::from_secs(60));
+        keys.push((key, times.len()));
+    }
+
+    keys.sort();
+    assert_eq!(keys, vec![(1, 2), (2, 1)]);
+
+    // closed by drain, not by timer
+    assert!(start.elapsed() < Duration::from_secs(60));
+    assert!(matches!(collect.await, ExitReason::UpstreamClosed));
+}